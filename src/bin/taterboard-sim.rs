@@ -0,0 +1,710 @@
+//! Replays a script of events through the handler against a fake Discord,
+//! then prints the leaderboards, pins, and saved json it ends up with.
+//!
+//! Usage: `taterboard-sim <script.jsonl> [data_dir]`
+//!
+//! If `data_dir` is given, data is loaded from and saved to it, so you can replay
+//! events on top of a copy of a real save directory.
+//! Otherwise a scratch directory is used.
+//!
+//! The script has one json event per line. Blank lines and lines starting with `#` are skipped.
+//! ```text
+//! {"event": "message", "guild": 1, "channel": 10, "id": 100, "author": 5, "name": "alice", "content": "hot take"}
+//! {"event": "message", "guild": 1, "channel": 10, "id": 101, "author": 5, "attachments": [{"url": "https://cdn/a.png", "width": 64, "height": 64}]}
+//! {"event": "message", "guild": 1, "channel": 10, "id": 102, "author": 6, "content": "no", "reply_to": 100}
+//! {"event": "reaction_add", "guild": 1, "channel": 10, "message": 100, "user": 6}
+//! {"event": "reaction_remove", "guild": 1, "channel": 10, "message": 100, "user": 6, "emoji": "🥔"}
+//! {"event": "config", "guild": 1, "command": "set_threshold 3"}
+//! {"event": "preview", "guild": 1, "channel": 10, "message": 100}
+//! {"event": "delete", "guild": 1, "channel": 50, "message": 1152921504606846976, "quietly": true}
+//! ```
+//! Reactions use the guild's tater emoji unless `emoji` is given. The reacting member can
+//! also have `roles` and a `joined` unix timestamp, which otherwise is long ago, and can be
+//! a `bot`. Messages can be from a `bot`, a `webhook` ID, or the `system`, can say who
+//! PluralKit `proxied_for`, and can be in an `nsfw` channel.
+//! Deletes can be of messages or pins, which get IDs counting up from 2^60. With `quietly`,
+//! the handler isn't told, like when a gateway event is missed.
+//! Config events take the same commands admins type after the trigger word.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    env,
+    error::Error,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use serenity::{
+    async_trait,
+    model::{
+        channel::ReactionType,
+        id::{ChannelId, GuildId, MessageId, RoleId, UserId, WebhookId},
+    },
+};
+
+use taterboard::{
+    discord::{
+        Discord, MessageAuthor, NotFound, PinEmbed, PinMessage, PinWebhook, SourceAttachment,
+        SourceEmbed, SourceMessage, SourceReply, SourceSticker, WebhookPin,
+    },
+    handler::{HandlerWrapper, ReactingMember, TaterReaction},
+    storage::JsonDirStorage,
+};
+
+/// What the simulated bot thinks its own user ID is
+const BOT_UID: UserId = UserId(1);
+
+#[derive(Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum SimEvent {
+    Message(SimMessage),
+    ReactionAdd(SimReaction),
+    ReactionRemove(SimReaction),
+    Config {
+        guild: u64,
+        command: String,
+    },
+    Preview {
+        guild: u64,
+        channel: u64,
+        message: u64,
+    },
+    Delete {
+        guild: u64,
+        channel: u64,
+        message: u64,
+        #[serde(default)]
+        quietly: bool,
+    },
+}
+
+#[derive(Deserialize)]
+struct SimMessage {
+    guild: u64,
+    channel: u64,
+    id: u64,
+    author: u64,
+    /// Display name, defaults to `user<id>`
+    name: Option<String>,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    attachments: Vec<SimAttachment>,
+    #[serde(default)]
+    stickers: Vec<SimSticker>,
+    #[serde(default)]
+    embeds: Vec<SimEmbed>,
+    /// ID of an earlier message in the script this one replies to
+    reply_to: Option<u64>,
+    #[serde(default)]
+    bot: bool,
+    /// The webhook it was sent through, if it was
+    webhook: Option<u64>,
+    /// Whether it's a system message, like a join notice
+    #[serde(default)]
+    system: bool,
+    /// Whether it's in an NSFW channel. That marks the whole channel NSFW.
+    #[serde(default)]
+    nsfw: bool,
+    /// The account PluralKit says is behind it, if it was proxied
+    proxied_for: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct SimAttachment {
+    url: String,
+    filename: Option<String>,
+    #[serde(default)]
+    size: u64,
+    width: Option<u64>,
+    height: Option<u64>,
+    content_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SimSticker {
+    name: String,
+    image: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SimEmbed {
+    /// Like `image`, `gifv`, or `article`
+    kind: String,
+    url: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SimReaction {
+    guild: u64,
+    channel: u64,
+    message: u64,
+    user: u64,
+    emoji: Option<String>,
+    /// When they joined the guild, as a unix timestamp. Defaults to long ago.
+    joined: Option<i64>,
+    #[serde(default)]
+    roles: Vec<u64>,
+    #[serde(default)]
+    bot: bool,
+}
+
+/// A pretend Discord that only knows about messages from the script
+/// and the pins the handler sent
+struct FakeDiscord {
+    state: Mutex<FakeState>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    messages: HashMap<MessageId, SourceMessage>,
+    /// Who sent each message in `messages`
+    authors: HashMap<MessageId, MessageAuthor>,
+    /// Channels marked NSFW
+    nsfw_channels: HashSet<ChannelId>,
+    /// What PluralKit knows about proxied messages
+    proxied_for: HashMap<MessageId, UserId>,
+    /// Pins by their ID, along with the channel they're in
+    pins: BTreeMap<MessageId, (ChannelId, PinMessage)>,
+    /// Pins sent through webhooks, along with the webhook
+    webhook_pins: BTreeMap<MessageId, (PinWebhook, WebhookPin)>,
+    /// Lines posted to mod logs, along with the channel
+    logs: Vec<(ChannelId, String)>,
+    /// Snowflake handed to the next pin
+    next_id: u64,
+}
+
+impl FakeDiscord {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(FakeState {
+                next_id: 1 << 60,
+                ..FakeState::default()
+            }),
+        }
+    }
+
+    /// Add a message from the script. `proxied_for` is who PluralKit says sent it.
+    fn add_message(
+        &self,
+        message: SourceMessage,
+        author: MessageAuthor,
+        proxied_for: Option<UserId>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(user) = proxied_for {
+            state.proxied_for.insert(message.id, user);
+        }
+        state.authors.insert(message.id, author);
+        state.messages.insert(message.id, message);
+    }
+
+    /// Mark a channel NSFW, from then on
+    fn mark_nsfw(&self, channel: ChannelId) {
+        self.state.lock().unwrap().nsfw_channels.insert(channel);
+    }
+
+    /// Delete a message or pin, as if someone else did it.
+    /// Returns whether there was anything to delete.
+    fn delete(&self, channel: ChannelId, message: MessageId) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let in_channel = match state.messages.get(&message) {
+            Some(msg) => msg.channel_id == channel,
+            None => match (state.pins.get(&message), state.webhook_pins.get(&message)) {
+                (Some((pin_channel, _)), _) => *pin_channel == channel,
+                (None, Some((webhook, _))) => webhook.channel == channel,
+                (None, None) => false,
+            },
+        };
+        if in_channel {
+            state.messages.remove(&message);
+            state.authors.remove(&message);
+            state.pins.remove(&message);
+            state.webhook_pins.remove(&message);
+        }
+        in_channel
+    }
+
+    /// What Discord would say about a reply to this message
+    fn reply_to(&self, message: MessageId) -> Result<SourceReply, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        let msg = state
+            .messages
+            .get(&message)
+            .ok_or_else(|| anyhow!("Replying to unknown message {}", message))?;
+        Ok(SourceReply {
+            author_name: msg.author_name.clone(),
+            content: msg.content.clone(),
+            link: msg.link(),
+        })
+    }
+}
+
+#[async_trait]
+impl Discord for FakeDiscord {
+    async fn get_message(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<SourceMessage, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        match state.messages.get(&message) {
+            Some(msg) if msg.channel_id == channel => Ok(msg.clone()),
+            _ => Err(not_found(format!(
+                "Unknown message {} in channel {}",
+                message, channel
+            ))),
+        }
+    }
+
+    async fn get_author(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<MessageAuthor, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        match (state.messages.get(&message), state.authors.get(&message)) {
+            (Some(msg), Some(author)) if msg.channel_id == channel => Ok(author.clone()),
+            _ => Err(not_found(format!(
+                "Unknown message {} in channel {}",
+                message, channel
+            ))),
+        }
+    }
+
+    async fn is_nsfw(&self, channel: ChannelId) -> Result<bool, anyhow::Error> {
+        Ok(self.state.lock().unwrap().nsfw_channels.contains(&channel))
+    }
+
+    async fn send_pin(
+        &self,
+        channel: ChannelId,
+        pin: &PinMessage,
+    ) -> Result<MessageId, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let id = MessageId(state.next_id);
+        state.next_id += 1;
+        state.pins.insert(id, (channel, pin.clone()));
+        Ok(id)
+    }
+
+    async fn edit_pin_header(
+        &self,
+        channel: ChannelId,
+        pin: MessageId,
+        content: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.pins.get_mut(&pin) {
+            Some((pin_channel, pin)) if *pin_channel == channel => {
+                pin.content = content.to_owned();
+                Ok(())
+            }
+            _ => Err(not_found(format!(
+                "Unknown pin {} in channel {}",
+                pin, channel
+            ))),
+        }
+    }
+
+    async fn edit_pin(
+        &self,
+        channel: ChannelId,
+        pin: MessageId,
+        new_pin: &PinMessage,
+    ) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.pins.get_mut(&pin) {
+            Some((pin_channel, pin)) if *pin_channel == channel => {
+                *pin = new_pin.clone();
+                Ok(())
+            }
+            _ => Err(not_found(format!(
+                "Unknown pin {} in channel {}",
+                pin, channel
+            ))),
+        }
+    }
+
+    async fn delete_message(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let pin_channel = match (state.pins.get(&message), state.webhook_pins.get(&message)) {
+            (Some((pin_channel, _)), _) => *pin_channel,
+            (None, Some((webhook, _))) => webhook.channel,
+            (None, None) => {
+                return Err(not_found(format!(
+                    "Unknown pin {} in channel {}",
+                    message, channel
+                )))
+            }
+        };
+        if pin_channel != channel {
+            return Err(not_found(format!(
+                "Unknown pin {} in channel {}",
+                message, channel
+            )));
+        }
+        state.pins.remove(&message);
+        state.webhook_pins.remove(&message);
+        Ok(())
+    }
+
+    async fn create_webhook(&self, channel: ChannelId) -> Result<PinWebhook, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let id = WebhookId(state.next_id);
+        state.next_id += 1;
+        Ok(PinWebhook {
+            id,
+            token: "sim".to_owned(),
+            channel,
+        })
+    }
+
+    async fn send_webhook_pin(
+        &self,
+        webhook: &PinWebhook,
+        pin: &WebhookPin,
+    ) -> Result<MessageId, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let id = MessageId(state.next_id);
+        state.next_id += 1;
+        state
+            .webhook_pins
+            .insert(id, (webhook.clone(), pin.clone()));
+        Ok(id)
+    }
+
+    async fn edit_webhook_pin(
+        &self,
+        webhook: &PinWebhook,
+        pin: MessageId,
+        footer: &PinEmbed,
+    ) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.webhook_pins.get_mut(&pin) {
+            Some((sent_by, pin)) if sent_by == webhook => {
+                pin.footer = footer.clone();
+                Ok(())
+            }
+            _ => Err(not_found(format!(
+                "Webhook {} didn't send {}",
+                webhook.id, pin
+            ))),
+        }
+    }
+
+    async fn delete_webhook_pin(
+        &self,
+        webhook: &PinWebhook,
+        pin: MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.webhook_pins.get(&pin) {
+            Some((sent_by, _)) if sent_by == webhook => {
+                state.webhook_pins.remove(&pin);
+                Ok(())
+            }
+            _ => Err(not_found(format!(
+                "Webhook {} didn't send {}",
+                webhook.id, pin
+            ))),
+        }
+    }
+
+    async fn send_log(&self, channel: ChannelId, text: &str) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.logs.push((channel, text.to_owned()));
+        Ok(())
+    }
+
+    async fn proxied_for(&self, message: MessageId) -> Result<Option<UserId>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.proxied_for.get(&message).copied())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init_from_env(env_logger::Env::default());
+
+    let mut args = env::args().skip(1);
+    let script_path = args
+        .next()
+        .ok_or_else(|| anyhow!("Usage: taterboard-sim <script.jsonl> [data_dir]"))?;
+    let data_dir = match args.next() {
+        Some(dir) => PathBuf::from(dir),
+        None => env::temp_dir().join(format!("taterboard-sim-{}", std::process::id())),
+    };
+
+    let script = fs::read_to_string(&script_path)
+        .with_context(|| format!("reading script {}", script_path))?;
+    let wrapper = HandlerWrapper::new(Arc::new(JsonDirStorage::new(data_dir.clone())?))?;
+    wrapper.set_bot_uid(BOT_UID).await;
+    let discord = FakeDiscord::new();
+
+    for (line_idx, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let event: SimEvent = serde_json::from_str(line)
+            .with_context(|| format!("parsing line {} of the script", line_idx + 1))?;
+        run_event(&wrapper, &discord, event)
+            .await
+            .with_context(|| format!("running line {} of the script", line_idx + 1))?;
+        // There's no waiting around in a simulation
+        wrapper.drain_pins(&discord).await;
+    }
+
+    wrapper.save_all().await?;
+    print_results(&wrapper, &discord, &data_dir).await?;
+    Ok(())
+}
+
+async fn run_event(
+    wrapper: &HandlerWrapper,
+    discord: &FakeDiscord,
+    event: SimEvent,
+) -> Result<(), anyhow::Error> {
+    match event {
+        SimEvent::Message(msg) => {
+            let attachments = msg
+                .attachments
+                .into_iter()
+                .map(|att| {
+                    let filename = match att.filename {
+                        Some(it) => it,
+                        None => att.url.rsplit('/').next().unwrap_or_default().to_owned(),
+                    };
+                    SourceAttachment {
+                        filename,
+                        url: att.url,
+                        size: att.size,
+                        dimensions: att.width.zip(att.height),
+                        content_type: att.content_type,
+                    }
+                })
+                .collect();
+            let reply_to = match msg.reply_to {
+                Some(id) => Some(discord.reply_to(MessageId(id))?),
+                None => None,
+            };
+            let author_name = match msg.name {
+                Some(it) => it,
+                None => format!("user{}", msg.author),
+            };
+            let proxied_for = msg.proxied_for.map(UserId);
+            if msg.nsfw {
+                discord.mark_nsfw(ChannelId(msg.channel));
+            }
+            discord.add_message(
+                SourceMessage {
+                    id: MessageId(msg.id),
+                    channel_id: ChannelId(msg.channel),
+                    guild_id: Some(GuildId(msg.guild)),
+                    author_id: UserId(msg.author),
+                    author_name,
+                    author_avatar: String::new(),
+                    content: msg.content,
+                    attachments,
+                    stickers: msg
+                        .stickers
+                        .into_iter()
+                        .map(|sticker| SourceSticker {
+                            name: sticker.name,
+                            image: sticker.image,
+                        })
+                        .collect(),
+                    embeds: msg
+                        .embeds
+                        .into_iter()
+                        .map(|embed| SourceEmbed {
+                            kind: embed.kind,
+                            url: embed.url,
+                            title: embed.title,
+                            description: embed.description,
+                            image: embed.image,
+                        })
+                        .collect(),
+                    reply_to,
+                },
+                MessageAuthor {
+                    id: MessageId(msg.id),
+                    author_id: UserId(msg.author),
+                    bot: msg.bot || msg.webhook.is_some(),
+                    webhook_id: msg.webhook.map(WebhookId),
+                    system: msg.system,
+                },
+                proxied_for,
+            );
+        }
+        SimEvent::ReactionAdd(reaction) => {
+            let reaction = to_tater_reaction(wrapper, reaction).await?;
+            wrapper.on_reaction_add(discord, &reaction).await;
+        }
+        SimEvent::ReactionRemove(reaction) => {
+            let reaction = to_tater_reaction(wrapper, reaction).await?;
+            wrapper.on_reaction_remove(&reaction).await;
+        }
+        SimEvent::Config { guild, command } => {
+            let reply = match wrapper
+                .run_pin_command(discord, GuildId(guild), &command)
+                .await
+            {
+                Some(reply) => reply?,
+                None => wrapper.run_config_command(GuildId(guild), &command).await?,
+            };
+            println!("[guild {}] {}: {}", guild, command, reply);
+        }
+        SimEvent::Preview {
+            guild,
+            channel,
+            message,
+        } => {
+            let pin = wrapper
+                .preview_pin(
+                    discord,
+                    GuildId(guild),
+                    ChannelId(channel),
+                    MessageId(message),
+                )
+                .await?;
+            print_pin(&format!("guild {} preview of {}", guild, message), &pin);
+        }
+        SimEvent::Delete {
+            guild,
+            channel,
+            message,
+            quietly,
+        } => {
+            if !discord.delete(ChannelId(channel), MessageId(message)) {
+                bail!("Unknown message {} in channel {}", message, channel);
+            }
+            if !quietly {
+                wrapper
+                    .on_messages_deleted(GuildId(guild), ChannelId(channel), &[MessageId(message)])
+                    .await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What Discord says about things that don't exist
+fn not_found(what: String) -> anyhow::Error {
+    anyhow::Error::new(NotFound).context(what)
+}
+
+async fn to_tater_reaction(
+    wrapper: &HandlerWrapper,
+    reaction: SimReaction,
+) -> Result<TaterReaction, anyhow::Error> {
+    let guild_id = GuildId(reaction.guild);
+    let emoji = match reaction.emoji {
+        Some(emoji) => ReactionType::try_from(emoji.as_str())?,
+        None => wrapper.tater_emoji(guild_id).await,
+    };
+    Ok(TaterReaction {
+        guild_id,
+        channel_id: ChannelId(reaction.channel),
+        message_id: MessageId(reaction.message),
+        user_id: UserId(reaction.user),
+        emoji,
+        member: Some(ReactingMember {
+            joined_at: Some(reaction.joined.unwrap_or(0)),
+            roles: reaction.roles.into_iter().map(RoleId).collect(),
+        }),
+        by_bot: reaction.bot,
+    })
+}
+
+async fn print_results(
+    wrapper: &HandlerWrapper,
+    discord: &FakeDiscord,
+    data_dir: &std::path::Path,
+) -> Result<(), anyhow::Error> {
+    let (pins, webhook_pins, logs) = {
+        let state = discord.state.lock().unwrap();
+        (
+            state.pins.clone(),
+            state.webhook_pins.clone(),
+            state.logs.clone(),
+        )
+    };
+    for guild in wrapper.guild_ids().await {
+        println!("=== Guild {} ===", guild);
+
+        println!("--- Leaderboards ---");
+        for board in &["receivers", "givers"] {
+            let (title, body, footer) = wrapper.leaderboard(guild, board, 1, BOT_UID).await?;
+            println!("{}\n{}{}\n", title, body, footer);
+        }
+
+        println!("--- Saved json ---");
+        for suffix in &["config", "taters"] {
+            let filename = format!("{}_{}.json", guild, suffix);
+            let json = fs::read_to_string(data_dir.join(&filename))?;
+            println!("{}: {}", filename, json);
+        }
+        println!();
+    }
+
+    println!("=== Pins ===");
+    for (id, (channel, pin)) in pins {
+        print_pin(&format!("{} in channel {}", id, channel), &pin);
+    }
+    for (id, (webhook, pin)) in webhook_pins {
+        println!(
+            "[{} in channel {} via webhook {}] {}: {}",
+            id, webhook.channel, webhook.id, pin.username, pin.content
+        );
+        for file in &pin.files {
+            println!("  file: {}", file);
+        }
+        println!("  {}", pin.footer.description.replace('\n', "\n  "));
+        if let Some(footer) = &pin.footer.footer {
+            println!("  {}", footer);
+        }
+    }
+
+    if !logs.is_empty() {
+        println!("=== Mod logs ===");
+        for (channel, text) in logs {
+            println!("[channel {}] {}", channel, text);
+        }
+    }
+    Ok(())
+}
+
+fn print_pin(label: &str, pin: &PinMessage) {
+    println!("[{}] {}", label, pin.content);
+    for embed in &pin.embeds {
+        if !embed.author_name.is_empty() {
+            println!("  {}: {}", embed.author_name, embed.description);
+        } else if let Some(title) = &embed.title {
+            println!("  {}: {}", title, embed.description);
+        } else if !embed.description.is_empty() {
+            println!("  {}", embed.description);
+        }
+        for (name, value, _) in &embed.fields {
+            println!("  [{}] {}", name, value);
+        }
+        if let Some(image) = &embed.image {
+            println!("  image: {}", image);
+        }
+        if let Some(footer) = &embed.footer {
+            println!("  {}", footer);
+        }
+    }
+}
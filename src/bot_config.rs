@@ -0,0 +1,127 @@
+//! Settings for the bot as a whole, as opposed to the per-guild `Config`
+
+use std::{fs, net::SocketAddr, path::Path, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use serenity::model::id::GuildId;
+
+use crate::{
+    handler::{Registration, Schedule},
+    storage::StorageKind,
+};
+
+/// What can go in the `--config` file.
+/// Everything is optional; command line flags and environment variables win over it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+    /// Bot token. Prefer `TATERBOARD_TOKEN` if the file might get shared around.
+    pub token: Option<String>,
+    pub app_id: Option<u64>,
+    /// Directory guild data is saved in
+    pub data_dir: Option<PathBuf>,
+    /// Log filter, in the same format as `RUST_LOG`
+    pub log_level: Option<String>,
+    pub storage: Option<StorageKind>,
+    /// Where to register slash commands
+    pub commands: Option<CommandScope>,
+    /// Guild to register slash commands to instead, for development.
+    /// Wins over `commands`.
+    pub dev_guild: Option<u64>,
+    /// Where to serve metrics, if anywhere. Keep it local.
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Minutes between saves
+    pub save_minutes: Option<u64>,
+    /// Minutes between status changes
+    pub status_minutes: Option<u64>,
+    /// See `Schedule::jitter`
+    pub jitter: Option<f64>,
+    /// Seconds each step of shutting down gets
+    pub shutdown_timeout_secs: Option<u64>,
+}
+
+impl BotConfig {
+    /// Read a config file
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+        toml::from_str(&text).with_context(|| format!("parsing {:?}", path))
+    }
+
+    /// Where slash commands get registered
+    pub fn registration(&self) -> Registration {
+        match (self.dev_guild, self.commands) {
+            (Some(guild), _) => Registration::DevGuild(GuildId(guild)),
+            (None, Some(CommandScope::PerGuild)) => Registration::PerGuild,
+            (None, Some(CommandScope::Global)) | (None, None) => Registration::Global,
+        }
+    }
+
+    /// The periodic job schedule, with defaults for anything not set.
+    /// Errors if a time is zero or too big, since the jobs would spin or overflow.
+    pub fn schedule(&self) -> Result<Schedule, anyhow::Error> {
+        let default = Schedule::default();
+        Ok(Schedule {
+            save_every: minutes("save_minutes", self.save_minutes)?.unwrap_or(default.save_every),
+            status_every: minutes("status_minutes", self.status_minutes)?
+                .unwrap_or(default.status_every),
+            jitter: self.jitter.unwrap_or(default.jitter),
+        })
+    }
+}
+
+/// A number of minutes from setting `name`, which has to be at least 1
+fn minutes(name: &str, value: Option<u64>) -> Result<Option<Duration>, anyhow::Error> {
+    let value = match value {
+        Some(it) => it,
+        None => return Ok(None),
+    };
+    if value == 0 {
+        bail!("`{}` has to be at least 1", name);
+    }
+    let secs = value
+        .checked_mul(60)
+        .ok_or_else(|| anyhow!("`{}` is too big: {}", name, value))?;
+    Ok(Some(Duration::from_secs(secs)))
+}
+
+/// Where slash commands get registered, outside of development
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandScope {
+    /// Once, for every guild
+    Global,
+    /// In each guild separately, without the command groups it's turned off
+    PerGuild,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule_rejects_times_that_would_spin_or_overflow() {
+        let config = BotConfig {
+            save_minutes: Some(0),
+            ..BotConfig::default()
+        };
+        let error = config.schedule().unwrap_err().to_string();
+        assert!(error.contains("save_minutes"), "{}", error);
+
+        let config = BotConfig {
+            status_minutes: Some(u64::MAX),
+            ..BotConfig::default()
+        };
+        let error = config.schedule().unwrap_err().to_string();
+        assert!(error.contains("status_minutes"), "{}", error);
+
+        let config = BotConfig {
+            save_minutes: Some(5),
+            ..BotConfig::default()
+        };
+        let schedule = config.schedule().unwrap();
+        assert_eq!(schedule.save_every, Duration::from_secs(5 * 60));
+        assert_eq!(schedule.status_every, Schedule::default().status_every);
+    }
+}
@@ -0,0 +1,666 @@
+//! Everything the handler needs from Discord, behind a trait so it can be faked
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+
+use anyhow::Context as AnyhowContext;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use serenity::{
+    async_trait,
+    cache::Cache,
+    client::Context,
+    http::{AttachmentType, Http, HttpError, StatusCode},
+    model::{
+        channel::{Channel, MessageType, StickerFormatType},
+        id::{ChannelId, GuildId, MessageId, UserId, WebhookId},
+    },
+    CacheAndHttp, Error as SerenityError,
+};
+
+use crate::metrics::{DiscordOp, METRICS};
+
+/// The bits of Discord the handler talks to
+#[async_trait]
+pub trait Discord: Send + Sync {
+    /// Fetch a message, with everything needed to pin it already resolved
+    async fn get_message(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<SourceMessage, anyhow::Error>;
+
+    /// Fetch just who sent a message, which is all counting a tater needs
+    async fn get_author(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<MessageAuthor, anyhow::Error>;
+
+    /// Whether a channel is marked NSFW
+    async fn is_nsfw(&self, channel: ChannelId) -> Result<bool, anyhow::Error>;
+
+    /// Send a brand new pin message and return its ID
+    async fn send_pin(
+        &self,
+        channel: ChannelId,
+        pin: &PinMessage,
+    ) -> Result<MessageId, anyhow::Error>;
+
+    /// Change the header text of an existing pin message
+    async fn edit_pin_header(
+        &self,
+        channel: ChannelId,
+        pin: MessageId,
+        content: &str,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Replace everything in an existing pin message
+    async fn edit_pin(
+        &self,
+        channel: ChannelId,
+        pin: MessageId,
+        new_pin: &PinMessage,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Delete a message
+    async fn delete_message(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Make a webhook in a channel to send pins through
+    async fn create_webhook(&self, channel: ChannelId) -> Result<PinWebhook, anyhow::Error>;
+
+    /// Send a pin through a webhook and return its ID
+    async fn send_webhook_pin(
+        &self,
+        webhook: &PinWebhook,
+        pin: &WebhookPin,
+    ) -> Result<MessageId, anyhow::Error>;
+
+    /// Swap out the footer of a pin sent through a webhook
+    async fn edit_webhook_pin(
+        &self,
+        webhook: &PinWebhook,
+        pin: MessageId,
+        footer: &PinEmbed,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Delete a pin sent through a webhook
+    async fn delete_webhook_pin(
+        &self,
+        webhook: &PinWebhook,
+        pin: MessageId,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Post a line of text to a mod log channel, without pinging anyone it mentions
+    async fn send_log(&self, channel: ChannelId, text: &str) -> Result<(), anyhow::Error>;
+
+    /// Ask PluralKit whose account a message it proxied through a webhook was really from.
+    /// None if PluralKit doesn't know the message.
+    async fn proxied_for(&self, message: MessageId) -> Result<Option<UserId>, anyhow::Error>;
+}
+
+/// A message that might get pinned
+#[derive(Debug, Clone)]
+pub struct SourceMessage {
+    pub id: MessageId,
+    pub channel_id: ChannelId,
+    pub guild_id: Option<GuildId>,
+    pub author_id: UserId,
+    /// Nickname if they have one, otherwise username
+    pub author_name: String,
+    pub author_avatar: String,
+    /// Content with mentions already made safe
+    pub content: String,
+    pub attachments: Vec<SourceAttachment>,
+    pub stickers: Vec<SourceSticker>,
+    /// Embeds Discord added, like link previews and Tenor GIFs
+    pub embeds: Vec<SourceEmbed>,
+    /// The message this one replied to, if it still exists
+    pub reply_to: Option<SourceReply>,
+}
+
+impl SourceMessage {
+    /// Link to jump to this message
+    pub fn link(&self) -> String {
+        self.id.link(self.channel_id, self.guild_id)
+    }
+}
+
+/// Who sent a message, and how
+#[derive(Debug, Clone)]
+pub struct MessageAuthor {
+    pub id: MessageId,
+    pub author_id: UserId,
+    /// Whether it was sent by a bot, which includes webhooks
+    pub bot: bool,
+    /// The webhook it was sent through, if it was
+    pub webhook_id: Option<WebhookId>,
+    /// Whether Discord posted it about something happening, like someone joining or boosting
+    pub system: bool,
+}
+
+/// Just enough of a replied-to message to say what the reply was about
+#[derive(Debug, Clone)]
+pub struct SourceReply {
+    pub author_name: String,
+    /// Content with mentions already made safe
+    pub content: String,
+    /// Link to jump to it
+    pub link: String,
+}
+
+/// A file attached to a message
+#[derive(Debug, Clone)]
+pub struct SourceAttachment {
+    pub filename: String,
+    pub url: String,
+    pub size: u64,
+    /// Width and height, only present for images and videos
+    pub dimensions: Option<(u64, u64)>,
+    /// MIME type, if Discord worked it out
+    pub content_type: Option<String>,
+}
+
+impl SourceAttachment {
+    /// Whether whoever sent it marked it as a spoiler
+    pub fn is_spoiler(&self) -> bool {
+        self.filename.starts_with("SPOILER_")
+    }
+
+    /// Whether it can be shown as an embed image
+    pub fn is_image(&self) -> bool {
+        match &self.content_type {
+            Some(content_type) => content_type.starts_with("image/"),
+            // Videos have dimensions too, so go by the extension
+            None => {
+                let filename = self.filename.to_lowercase();
+                self.dimensions.is_some()
+                    && [".png", ".jpg", ".jpeg", ".gif", ".webp"]
+                        .iter()
+                        .any(|ext| filename.ends_with(ext))
+            }
+        }
+    }
+}
+
+/// A sticker sent with a message
+#[derive(Debug, Clone)]
+pub struct SourceSticker {
+    pub name: String,
+    /// Link to a picture of it, unless it's a kind we can't show
+    pub image: Option<String>,
+}
+
+/// An embed Discord put on a message
+#[derive(Debug, Clone)]
+pub struct SourceEmbed {
+    /// Discord's name for what it is, like `image`, `gifv`, `video`, or `article`
+    pub kind: String,
+    pub url: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// The embed's image, or its thumbnail if it doesn't have one
+    pub image: Option<String>,
+}
+
+/// A pin message, ready to send
+#[derive(Debug, Clone)]
+pub struct PinMessage {
+    /// The header, like `🥔 5`
+    pub content: String,
+    /// The first one is the main one. Embeds sharing its `url` show up as a gallery with it.
+    pub embeds: Vec<PinEmbed>,
+}
+
+/// Anything left empty isn't shown
+#[derive(Debug, Clone, Default)]
+pub struct PinEmbed {
+    pub author_name: String,
+    pub author_icon: String,
+    pub title: Option<String>,
+    pub url: Option<String>,
+    pub description: String,
+    /// Name, value, and whether it's inline
+    pub fields: Vec<(String, String, bool)>,
+    pub footer: Option<String>,
+    pub image: Option<String>,
+}
+
+/// A webhook pins can be sent through
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinWebhook {
+    pub id: WebhookId,
+    pub token: String,
+    /// The channel it posts to
+    pub channel: ChannelId,
+}
+
+/// A pin to send through a webhook, so it looks like its author sent it
+#[derive(Debug, Clone)]
+pub struct WebhookPin {
+    pub username: String,
+    pub avatar_url: String,
+    pub content: String,
+    /// Links to files to upload again
+    pub files: Vec<String>,
+    /// Small print with the taters and a link back to the original
+    pub footer: PinEmbed,
+}
+
+/// Talks to the real Discord through serenity
+pub struct SerenityDiscord {
+    http: Arc<Http>,
+    cache: Arc<Cache>,
+}
+
+impl SerenityDiscord {
+    pub fn new(ctx: &Context) -> Self {
+        Self {
+            http: Arc::clone(&ctx.http),
+            cache: Arc::clone(&ctx.cache),
+        }
+    }
+
+    /// For when there's a client but no event context, like while shutting down
+    pub fn from_cache_and_http(cache_and_http: &CacheAndHttp) -> Self {
+        Self {
+            http: Arc::clone(&cache_and_http.http),
+            cache: Arc::clone(&cache_and_http.cache),
+        }
+    }
+}
+
+#[async_trait]
+impl Discord for SerenityDiscord {
+    async fn get_message(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<SourceMessage, anyhow::Error> {
+        let message = metered(
+            DiscordOp::GetMessage,
+            self.http.get_message(channel.0, message.0),
+        )
+        .await
+        .with_context(|| format!("getting message {} from channel {}", message, channel))?;
+        let content = message.content_safe(&self.cache).await;
+        let author_name = message
+            .author_nick(&self.http)
+            .await
+            .unwrap_or_else(|| message.author.name.clone());
+        let attachments = message
+            .attachments
+            .iter()
+            .map(|att| SourceAttachment {
+                filename: att.filename.clone(),
+                url: att.url.clone(),
+                size: att.size,
+                dimensions: att.dimensions(),
+                content_type: att.content_type.clone(),
+            })
+            .collect();
+        let stickers = message
+            .stickers
+            .iter()
+            .map(|sticker| SourceSticker {
+                name: sticker.name.clone(),
+                image: match sticker.format_type {
+                    StickerFormatType::Png | StickerFormatType::Apng => Some(format!(
+                        "https://media.discordapp.net/stickers/{}.png",
+                        sticker.id
+                    )),
+                    // Lottie stickers are animations we can't embed
+                    _ => None,
+                },
+            })
+            .collect();
+        let embeds = message
+            .embeds
+            .iter()
+            .map(|embed| SourceEmbed {
+                kind: embed.kind.clone(),
+                url: embed.url.clone(),
+                title: embed.title.clone(),
+                description: embed.description.clone(),
+                image: match (&embed.image, &embed.thumbnail) {
+                    (Some(image), _) => Some(image.url.clone()),
+                    (None, Some(thumbnail)) => Some(thumbnail.url.clone()),
+                    (None, None) => None,
+                },
+            })
+            .collect();
+        let reply_to = match &message.referenced_message {
+            Some(replied) => Some(SourceReply {
+                author_name: replied
+                    .author_nick(&self.http)
+                    .await
+                    .unwrap_or_else(|| replied.author.name.clone()),
+                content: replied.content_safe(&self.cache).await,
+                // Discord leaves the guild off referenced messages
+                link: replied.id.link(replied.channel_id, message.guild_id),
+            }),
+            None => None,
+        };
+
+        Ok(SourceMessage {
+            id: message.id,
+            channel_id: message.channel_id,
+            guild_id: message.guild_id,
+            author_id: message.author.id,
+            author_name,
+            author_avatar: message.author.face(),
+            content,
+            attachments,
+            stickers,
+            embeds,
+            reply_to,
+        })
+    }
+
+    async fn get_author(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<MessageAuthor, anyhow::Error> {
+        let message = metered(
+            DiscordOp::GetMessage,
+            self.http.get_message(channel.0, message.0),
+        )
+        .await
+        .with_context(|| format!("getting message {} from channel {}", message, channel))?;
+        Ok(MessageAuthor {
+            id: message.id,
+            author_id: message.author.id,
+            bot: message.author.bot,
+            webhook_id: message.webhook_id,
+            system: !matches!(
+                message.kind,
+                MessageType::Regular | MessageType::InlineReply | MessageType::ApplicationCommand
+            ),
+        })
+    }
+
+    async fn is_nsfw(&self, channel: ChannelId) -> Result<bool, anyhow::Error> {
+        if let Some(it) = self.cache.guild_channel(channel).await {
+            return Ok(it.nsfw);
+        }
+        // Not every channel's cached, so ask Discord
+        let channel = channel
+            .to_channel(&self.http)
+            .await
+            .with_context(|| format!("checking whether channel {} is NSFW", channel))?;
+        Ok(match channel {
+            Channel::Guild(it) => it.nsfw,
+            _ => false,
+        })
+    }
+
+    async fn send_pin(
+        &self,
+        channel: ChannelId,
+        pin: &PinMessage,
+    ) -> Result<MessageId, anyhow::Error> {
+        // The builder only does one embed, so send the json ourselves
+        let json = pin_json(pin);
+        let send = self.http.send_message(channel.0, &json);
+        let msg = metered(DiscordOp::SendPin, send)
+            .await
+            .context("sending pin")?;
+        Ok(msg.id)
+    }
+
+    async fn edit_pin_header(
+        &self,
+        channel: ChannelId,
+        pin: MessageId,
+        content: &str,
+    ) -> Result<(), anyhow::Error> {
+        metered(
+            DiscordOp::EditPinHeader,
+            channel.edit_message(&self.http, pin, |m| m.content(content)),
+        )
+        .await
+        .context("updating pin text")?;
+        Ok(())
+    }
+
+    async fn edit_pin(
+        &self,
+        channel: ChannelId,
+        pin: MessageId,
+        new_pin: &PinMessage,
+    ) -> Result<(), anyhow::Error> {
+        let json = pin_json(new_pin);
+        let edit = self.http.edit_message(channel.0, pin.0, &json);
+        metered(DiscordOp::EditPin, edit)
+            .await
+            .context("redoing pin")?;
+        Ok(())
+    }
+
+    async fn delete_message(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<(), anyhow::Error> {
+        metered(
+            DiscordOp::DeleteMessage,
+            channel.delete_message(&self.http, message),
+        )
+        .await
+        .context("deleting pin")?;
+        Ok(())
+    }
+
+    async fn create_webhook(&self, channel: ChannelId) -> Result<PinWebhook, anyhow::Error> {
+        let body = json!({ "name": "Taterboard" });
+        let create = self.http.create_webhook(channel.0, &body);
+        let webhook = metered(DiscordOp::CreateWebhook, create)
+            .await
+            .with_context(|| format!("making a webhook in channel {}", channel))?;
+        Ok(PinWebhook {
+            id: webhook.id,
+            token: webhook
+                .token
+                .context("Discord didn't give the new webhook a token")?,
+            channel,
+        })
+    }
+
+    async fn send_webhook_pin(
+        &self,
+        webhook: &PinWebhook,
+        pin: &WebhookPin,
+    ) -> Result<MessageId, anyhow::Error> {
+        let mut map = Map::new();
+        map.insert("username".to_owned(), json!(pin.username));
+        map.insert("avatar_url".to_owned(), json!(pin.avatar_url));
+        map.insert("content".to_owned(), json!(pin.content));
+        map.insert("embeds".to_owned(), json!([embed_json(&pin.footer)]));
+        // Whatever they said, don't ping anyone twice
+        map.insert("allowed_mentions".to_owned(), json!({ "parse": [] }));
+        let files = pin
+            .files
+            .iter()
+            .map(|url| AttachmentType::Image(url))
+            .collect::<Vec<_>>();
+        let send =
+            self.http
+                .execute_webhook_with_files(webhook.id.0, &webhook.token, true, files, map);
+        let msg = metered(DiscordOp::SendWebhookPin, send)
+            .await
+            .context("sending pin through webhook")?
+            .context("Discord didn't send back the pin")?;
+        Ok(msg.id)
+    }
+
+    async fn edit_webhook_pin(
+        &self,
+        webhook: &PinWebhook,
+        pin: MessageId,
+        footer: &PinEmbed,
+    ) -> Result<(), anyhow::Error> {
+        let mut map = Map::new();
+        map.insert("embeds".to_owned(), json!([embed_json(footer)]));
+        let edit = self
+            .http
+            .edit_webhook_message(webhook.id.0, &webhook.token, pin.0, &map);
+        metered(DiscordOp::EditWebhookPin, edit)
+            .await
+            .context("updating webhook pin")?;
+        Ok(())
+    }
+
+    async fn delete_webhook_pin(
+        &self,
+        webhook: &PinWebhook,
+        pin: MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let delete = self
+            .http
+            .delete_webhook_message(webhook.id.0, &webhook.token, pin.0);
+        metered(DiscordOp::DeleteWebhookPin, delete)
+            .await
+            .context("deleting webhook pin")?;
+        Ok(())
+    }
+
+    async fn send_log(&self, channel: ChannelId, text: &str) -> Result<(), anyhow::Error> {
+        let json = json!({
+            "content": text,
+            "allowed_mentions": { "parse": [] },
+        });
+        let send = self.http.send_message(channel.0, &json);
+        metered(DiscordOp::SendLog, send)
+            .await
+            .context("sending to the mod log")?;
+        Ok(())
+    }
+
+    async fn proxied_for(&self, message: MessageId) -> Result<Option<UserId>, anyhow::Error> {
+        #[derive(Deserialize)]
+        struct ProxiedMessage {
+            sender: String,
+        }
+
+        let client = PLURALKIT.get_or_init(|| {
+            reqwest::Client::builder()
+                .user_agent("taterboard (https://github.com/williewillus/tater-board)")
+                .build()
+                .expect("building the PluralKit client")
+        });
+        let start = Instant::now();
+        let response = client
+            .get(format!("https://api.pluralkit.me/v2/messages/{}", message))
+            .send()
+            .await;
+        let ok = response.as_ref().is_ok_and(|response| {
+            response.status().is_success() || response.status() == reqwest::StatusCode::NOT_FOUND
+        });
+        METRICS.pluralkit_request(start.elapsed(), ok);
+        let response = response.context("asking PluralKit about a message")?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let proxied = response
+            .error_for_status()
+            .context("asking PluralKit about a message")?
+            .json::<ProxiedMessage>()
+            .await
+            .context("reading what PluralKit said about a message")?;
+        let sender = proxied
+            .sender
+            .parse::<u64>()
+            .with_context(|| format!("PluralKit said the sender was `{}`", proxied.sender))?;
+        Ok(Some(UserId(sender)))
+    }
+}
+
+/// Shared by every [`SerenityDiscord`], since they're made fresh for each event
+static PLURALKIT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// For a [`Discord`] that isn't backed by the real thing to say something doesn't exist,
+/// so [`is_not_found`] treats it like one of Discord's 404s
+#[derive(Debug)]
+pub struct NotFound;
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("not found")
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Whether something failed because Discord said it doesn't exist (anymore)
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| match cause.downcast_ref::<SerenityError>() {
+        Some(SerenityError::Http(http)) => matches!(
+            &**http,
+            HttpError::UnsuccessfulRequest(response) if response.status_code == StatusCode::NOT_FOUND
+        ),
+        _ => cause.is::<NotFound>(),
+    })
+}
+
+/// Lay out a pin message the way Discord's API wants it
+fn pin_json(pin: &PinMessage) -> Value {
+    json!({
+        "content": pin.content,
+        "embeds": pin.embeds.iter().map(embed_json).collect::<Vec<_>>(),
+        // Templates can mention people, but pins shouldn't ping them
+        "allowed_mentions": { "parse": [] },
+    })
+}
+
+/// Lay out an embed the way Discord's API wants it
+fn embed_json(embed: &PinEmbed) -> Value {
+    let mut json = Map::new();
+    if !embed.author_name.is_empty() {
+        let mut author = json!({ "name": embed.author_name });
+        if !embed.author_icon.is_empty() {
+            author["icon_url"] = json!(embed.author_icon);
+        }
+        json.insert("author".to_owned(), author);
+    }
+    if let Some(title) = &embed.title {
+        json.insert("title".to_owned(), json!(title));
+    }
+    if let Some(url) = &embed.url {
+        json.insert("url".to_owned(), json!(url));
+    }
+    if !embed.description.is_empty() {
+        json.insert("description".to_owned(), json!(embed.description));
+    }
+    if !embed.fields.is_empty() {
+        let fields = embed
+            .fields
+            .iter()
+            .map(|(name, value, inline)| json!({ "name": name, "value": value, "inline": inline }))
+            .collect::<Vec<_>>();
+        json.insert("fields".to_owned(), json!(fields));
+    }
+    if let Some(footer) = &embed.footer {
+        json.insert("footer".to_owned(), json!({ "text": footer }));
+    }
+    if let Some(image) = &embed.image {
+        json.insert("image".to_owned(), json!({ "url": image }));
+    }
+    Value::Object(json)
+}
+
+/// Await a call to Discord, keeping track of how long it took and whether it worked
+async fn metered<T, E>(op: DiscordOp, call: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let start = Instant::now();
+    let res = call.await;
+    METRICS.discord_request(op, start.elapsed(), res.is_ok());
+    res
+}
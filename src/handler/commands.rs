@@ -2,25 +2,34 @@
 
 use std::convert::TryFrom;
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
+use rand::seq::SliceRandom;
 use serenity::{
     client::Context,
     model::{
         channel::Message,
         channel::ReactionType,
-        id::{ChannelId, UserId},
+        id::{ChannelId, GuildId, MessageId, RoleId, UserId},
         interactions::Interaction,
         Permissions,
     },
     prelude::*,
 };
 
-use super::{Handler, HandlerWrapper};
+use super::{
+    pin_queue::PinKey,
+    pins::Moderation,
+    requirements::{format_hours, parse_hours},
+    template::{live_placeholder_in, LIVE_PLACEHOLDERS, PLACEHOLDERS},
+    CommandGroup, DeletedPins, Handler, HandlerWrapper, NsfwPins, OldMessages, PinMode,
+    PinTemplate, UserBan, WebhookMessages,
+};
+use crate::discord::{Discord, SerenityDiscord};
 
-async fn generate_leaderboard(
+fn generate_leaderboard(
     leaderboard: &str,
     page_num: usize, // 1-indexed
-    this: &mut Handler,
+    this: &Handler,
     user_id: UserId,
 ) -> Result<(String, String, String), anyhow::Error> {
     const PAGE_SIZE: usize = 10;
@@ -70,16 +79,13 @@ async fn generate_leaderboard(
         ));
     }
 
-    let asker_place = scores
-        .iter()
-        .enumerate()
-        .find_map(|(idx, (id, score))| {
-            if *id == user_id {
-                Some((idx + 1, score))
-            } else {
-                None
-            }
-        });
+    let asker_place = scores.iter().enumerate().find_map(|(idx, (id, score))| {
+        if *id == user_id {
+            Some((idx + 1, score))
+        } else {
+            None
+        }
+    });
     let (place, score) = match asker_place {
         Some((p, s)) => (p.to_string(), s.to_string()),
         None => ("?".to_string(), "?".to_string()),
@@ -93,15 +99,10 @@ async fn generate_leaderboard(
         total_pages
     );
 
-    return Ok((format!("Leaderboard - Taters {}", verb), board, footer));
+    Ok((format!("Leaderboard - Taters {}", verb), board, footer))
 }
 
-async fn generate_csv(
-    args: &[&str],
-    this: &mut Handler,
-    ctx: &Context,
-    msg: &Message,
-) -> Result<String, anyhow::Error> {
+fn generate_csv(args: &[&str], this: &Handler) -> Result<String, anyhow::Error> {
     fn generate(map: &std::collections::HashMap<UserId, u64>) -> String {
         let mut result = "uid,value".to_owned();
         for (k, v) in map {
@@ -110,12 +111,15 @@ async fn generate_csv(
         }
         result
     }
+    match args.first() {
+        Some(&"receivers") => Ok(generate(&this.taters_got)),
+        Some(&"givers") => Ok(generate(&this.taters_given)),
+        _ => Err(anyhow!("Unknown report")),
+    }
+}
+
+async fn send_csv(data: String, ctx: &Context, msg: &Message) -> Result<String, anyhow::Error> {
     use serenity::http::AttachmentType;
-    let data = match args.get(0) {
-        Some(&"receivers") => generate(&this.taters_got),
-        Some(&"givers") => generate(&this.taters_given),
-        _ => return Err(anyhow!("Unknown report")),
-    };
     let attachment = AttachmentType::Bytes {
         data: data.into_bytes().into(),
         filename: "stats.csv".to_owned(),
@@ -130,7 +134,7 @@ async fn generate_csv(
 
 fn set_pin_channel(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
     let channel_id = args
-        .get(0)
+        .first()
         .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
     let channel_id = ChannelId(channel_id.parse::<u64>()?);
     this.config.pin_channel = channel_id;
@@ -139,12 +143,14 @@ fn set_pin_channel(args: &[&str], this: &mut Handler) -> Result<String, anyhow::
     let channel_mention = channel_id.mention();
     if !existed {
         Ok(format!(
-            "Set pins channel to `{}` and added it to the blacklist",
+            "Set pins channel to `{}` and added it to the blacklist. \
+            Use `migrate_pins` to move the pins that are already up.",
             &channel_mention
         ))
     } else {
         Ok(format!(
-            "Set pins channel to `{}`, and it was already blacklisted",
+            "Set pins channel to `{}`, and it was already blacklisted. \
+            Use `migrate_pins` to move the pins that are already up.",
             &channel_mention
         ))
     }
@@ -152,7 +158,7 @@ fn set_pin_channel(args: &[&str], this: &mut Handler) -> Result<String, anyhow::
 
 fn set_threshold(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
     let threshold = args
-        .get(0)
+        .first()
         .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
     let threshold = threshold.parse::<u64>()?;
     this.config.threshold = threshold;
@@ -161,7 +167,7 @@ fn set_threshold(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Er
 
 fn blacklist(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
     let channel_id = args
-        .get(0)
+        .first()
         .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
     let channel_id = ChannelId(channel_id.parse::<u64>()?);
     let existed = !this.config.blacklisted_channels.insert(channel_id);
@@ -176,7 +182,7 @@ fn blacklist(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error>
 
 fn unblacklist(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
     let channel_id = args
-        .get(0)
+        .first()
         .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
     let channel_id = ChannelId(channel_id.parse::<u64>()?);
     let existed = this.config.blacklisted_channels.remove(&channel_id);
@@ -191,7 +197,7 @@ fn unblacklist(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Erro
 
 fn set_potato(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
     let emoji = args
-        .get(0)
+        .first()
         .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
     let potato_react = ReactionType::try_from(*emoji)?;
     let old_react = this.config.tater_emoji.to_string();
@@ -204,7 +210,7 @@ fn set_potato(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error
 
 fn admin(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
     let user_id = args
-        .get(0)
+        .first()
         .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
     let user_id = UserId(user_id.parse::<u64>()?);
     let existed = !this.config.admins.insert(user_id);
@@ -217,7 +223,7 @@ fn admin(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
 
 fn unadmin(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
     let user_id = args
-        .get(0)
+        .first()
         .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
     let user_id = UserId(user_id.parse::<u64>()?);
     let existed = this.config.admins.remove(&user_id);
@@ -228,7 +234,803 @@ fn unadmin(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
     }
 }
 
-async fn list_admins(this: &mut Handler) -> Result<String, anyhow::Error> {
+fn enable(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let group = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    let group = group.parse::<CommandGroup>()?;
+    if this.config.disabled_command_groups.remove(&group) {
+        Ok(format!("Turned on the {} commands", group))
+    } else {
+        Ok(format!("The {} commands were already on", group))
+    }
+}
+
+fn disable(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let group = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    let group = group.parse::<CommandGroup>()?;
+    if this.config.disabled_command_groups.insert(group) {
+        Ok(format!("Turned off the {} commands", group))
+    } else {
+        Ok(format!("The {} commands were already off", group))
+    }
+}
+
+fn show_replies(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let setting = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    this.config.show_replies = match *setting {
+        "on" => true,
+        "off" => false,
+        _ => bail!("Expected `on` or `off`, not `{}`", setting),
+    };
+    if this.config.show_replies {
+        Ok("New pins of replies will show what they replied to".to_owned())
+    } else {
+        Ok("New pins of replies won't show what they replied to".to_owned())
+    }
+}
+
+fn pin_mode(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let mode = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    this.config.pin_mode = match *mode {
+        "embed" => PinMode::Embed,
+        "webhook" => PinMode::Webhook,
+        _ => bail!("Expected `embed` or `webhook`, not `{}`", mode),
+    };
+    match this.config.pin_mode {
+        PinMode::Embed => Ok("New pins will be embeds posted by me".to_owned()),
+        PinMode::Webhook => Ok(
+            "New pins will be posted through a webhook, as whoever sent the message. \
+            I'll need the Manage Webhooks permission in the pin channel."
+                .to_owned(),
+        ),
+    }
+}
+
+fn deleted_pins(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let policy = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    this.config.deleted_pins = match *policy {
+        "clear" => DeletedPins::Clear,
+        "recreate" => DeletedPins::Recreate,
+        _ => bail!("Expected `clear` or `recreate`, not `{}`", policy),
+    };
+    match this.config.deleted_pins {
+        DeletedPins::Clear => Ok(
+            "Pins deleted by hand stay gone, like `unpin`. `allow_pin` lets them back.".to_owned(),
+        ),
+        DeletedPins::Recreate => Ok("Pins deleted by hand will be posted again".to_owned()),
+    }
+}
+
+fn nsfw_pins(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let policy = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    this.config.nsfw_pins = match *policy {
+        "allow" => NsfwPins::Allow,
+        "never" => NsfwPins::Never,
+        "spoiler" => NsfwPins::Spoiler,
+        "board" => {
+            let channel = args
+                .get(1)
+                .ok_or_else(|| anyhow!("Give the NSFW board's channel ID"))?;
+            let channel = ChannelId(channel.parse::<u64>()?);
+            this.config.blacklisted_channels.insert(channel);
+            NsfwPins::Board(channel)
+        }
+        _ => bail!(
+            "Expected `allow`, `never`, `spoiler`, or `board`, not `{}`",
+            policy
+        ),
+    };
+    match this.config.nsfw_pins {
+        NsfwPins::Allow => Ok("Messages from NSFW channels get pinned like any other".to_owned()),
+        NsfwPins::Never => Ok("Messages from NSFW channels won't be pinned. \
+            Use `rerender` to take down the ones that are already up."
+            .to_owned()),
+        NsfwPins::Spoiler => Ok(
+            "Pins of messages from NSFW channels will be hidden behind spoilers. \
+            Use `rerender` to hide the ones that are already up."
+                .to_owned(),
+        ),
+        NsfwPins::Board(channel) => Ok(format!(
+            "Messages from NSFW channels will be pinned in {}, which is now blacklisted. \
+            Use `rerender` or `migrate_pins` to move the ones that are already up.",
+            channel.mention()
+        )),
+    }
+}
+
+/// `require` on its own shows who can give taters, otherwise it changes one requirement
+fn require(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let requirements = &mut this.config.giver_requirements;
+    let value = args.get(1).copied();
+    let off = value == Some("off");
+    let role = || -> Result<Option<RoleId>, anyhow::Error> {
+        match value {
+            None => bail!("Give a role ID, or `off`"),
+            Some("off") => Ok(None),
+            Some(id) => Ok(Some(RoleId(id.parse::<u64>()?))),
+        }
+    };
+    let hours = || -> Result<u64, anyhow::Error> {
+        match value {
+            None => bail!("Give a time like `3d`, or `off`"),
+            Some("off") => Ok(0),
+            Some(time) => parse_hours(time),
+        }
+    };
+    match args.first() {
+        None => return Ok(requirements.describe()),
+        Some(&"account_age") => requirements.min_account_age_hours = hours()?,
+        Some(&"member_age") => requirements.min_member_hours = hours()?,
+        Some(&"role") => requirements.role = role()?,
+        Some(&"not_role") => requirements.deny_role = role()?,
+        Some(other) => bail!(
+            "Unknown requirement `{}`, try `account_age`, `member_age`, `role`, or `not_role`",
+            other
+        ),
+    }
+    let mut msg = format!(
+        "{} that requirement. Now:\n{}",
+        if off { "Turned off" } else { "Set" },
+        requirements.describe()
+    );
+    if this.config.mod_log_channel.is_none() {
+        msg.push_str("\nSet a `mod_log` channel to hear about taters that don't count.");
+    }
+    Ok(msg)
+}
+
+fn mod_log(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let channel = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    if *channel == "off" {
+        this.config.mod_log_channel = None;
+        return Ok("Turned off the mod log".to_owned());
+    }
+    let channel = ChannelId(channel.parse::<u64>()?);
+    this.config.mod_log_channel = Some(channel);
+    Ok(format!(
+        "Taters that don't count will be noted in {}",
+        channel.mention()
+    ))
+}
+
+/// `template` on its own shows the pin template, otherwise it changes one part of it
+fn template(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let template = &mut this.config.pin_template;
+    let text = args.get(1..).unwrap_or_default().join(" ");
+    // For the parts that can be left out, which are never updated after the pin's made
+    let text_or_off = || match text.as_str() {
+        "" => Err(anyhow!("Give some text, or `off` to leave it out")),
+        "off" => Ok(None),
+        _ => match live_placeholder_in(&text) {
+            Some(name) => Err(anyhow!(
+                "`{{{}}}` changes as taters come and go, so it can only go in the header",
+                name
+            )),
+            None => Ok(Some(text.clone())),
+        },
+    };
+    match args.first() {
+        None => {
+            let show = |part: &Option<String>| match part {
+                Some(text) => format!("`{}`", text),
+                None => "off".to_owned(),
+            };
+            let mut msg = format!(
+                "Pin template:\n- header: `{}`\n- author: {}\n- jump: {}\n- footer: {}\nPlaceholders:",
+                template.header,
+                if template.author { "on" } else { "off" },
+                show(&template.jump),
+                show(&template.footer),
+            );
+            for (name, what) in &PLACEHOLDERS {
+                let header_only = if LIVE_PLACEHOLDERS.contains(name) {
+                    " (header only)"
+                } else {
+                    ""
+                };
+                msg.push_str(&format!("\n- `{{{}}}`: {}{}", name, what, header_only));
+            }
+            Ok(msg)
+        }
+        Some(&"header") => {
+            if text.is_empty() {
+                bail!("Give some text for the header");
+            }
+            template.header = text;
+            Ok("Changed the pin header".to_owned())
+        }
+        Some(&"jump") => {
+            template.jump = text_or_off()?;
+            Ok("Changed the jump link".to_owned())
+        }
+        Some(&"footer") => {
+            template.footer = text_or_off()?;
+            Ok("Changed the pin footer".to_owned())
+        }
+        Some(&"author") => {
+            template.author = match text.as_str() {
+                "on" => true,
+                "off" => false,
+                _ => bail!("Expected `on` or `off`, not `{}`", text),
+            };
+            Ok("Changed whether pins show their author".to_owned())
+        }
+        Some(&"reset") => {
+            *template = PinTemplate::default();
+            Ok("Put the pin template back to how it started".to_owned())
+        }
+        Some(part) => Err(anyhow!(
+            "Unknown part `{}`, try one of: header, author, jump, footer, reset",
+            part
+        )),
+    }
+}
+
+fn list_command_groups(this: &Handler) -> Result<String, anyhow::Error> {
+    let mut msg = String::from("Command groups:");
+    for group in &CommandGroup::ALL {
+        let state = if this.config.disabled_command_groups.contains(group) {
+            "off"
+        } else {
+            "on"
+        };
+        msg.push_str(&format!("\n- {}: {}", group, state));
+    }
+    Ok(msg)
+}
+
+/// Which moderation command `cmd` is, if it's one
+fn moderation_of(cmd: &str) -> Option<Moderation> {
+    Some(match cmd {
+        "force_pin" => Moderation::Force,
+        "unpin" => Moderation::Unpin,
+        "allow_pin" => Moderation::Allow,
+        "lock" => Moderation::Lock,
+        "unlock" => Moderation::Unlock,
+        _ => return None,
+    })
+}
+
+/// If `command` is in a group this guild has turned off, which one
+fn disabled_group(this: &Handler, command: &str) -> Option<CommandGroup> {
+    CommandGroup::of_command(command)
+        .filter(|group| this.config.disabled_command_groups.contains(group))
+}
+
+/// Link to a random pin, for `random_pin`
+fn random_pin(this: &Handler, guild: GuildId) -> String {
+    let pinned = this
+        .tatered_messages
+        .values()
+        .filter_map(|msg| msg.pin_id.map(|pin| (pin, msg.pin_channel, msg.count)))
+        .collect::<Vec<_>>();
+    match pinned.choose(&mut rand::thread_rng()) {
+        Some((pin, pin_channel, count)) => format!(
+            "Here's one with {} taters: {}",
+            count,
+            pin.link(pin_channel.unwrap_or(this.config.pin_channel), Some(guild))
+        ),
+        None => "Nothing's been pinned here yet!".to_owned(),
+    }
+}
+
+/// Redo every pin in the guild, or check on or stop that
+async fn rerender(
+    args: &[&str],
+    wrapper: &HandlerWrapper,
+    guild: GuildId,
+) -> Result<String, anyhow::Error> {
+    match args.first() {
+        None => {
+            let (queued, unknown) = wrapper.rerender_pins(guild).await;
+            let mut msg = format!(
+                "Redoing {} pins, about one a second. Check on it with `rerender status`.",
+                queued
+            );
+            if unknown > 0 {
+                msg.push_str(&format!(
+                    "\n{} pins are from before I kept track of channels, so I can't redo them.",
+                    unknown
+                ));
+            }
+            Ok(msg)
+        }
+        Some(&"status") => match wrapper.rerenders_left(guild) {
+            0 => Ok("Not redoing any pins".to_owned()),
+            left => Ok(format!("{} pins left to redo", left)),
+        },
+        Some(&"cancel") => Ok(format!(
+            "Stopped redoing pins with {} left to go",
+            wrapper.cancel_rerenders(guild)
+        )),
+        Some(other) => Err(anyhow!(
+            "Unknown option `{}`, try `status` or `cancel`",
+            other
+        )),
+    }
+}
+
+/// Keep someone's taters from counting, and take back the ones that did if asked to
+async fn ban(
+    args: &[&str],
+    wrapper: &HandlerWrapper,
+    guild: GuildId,
+) -> Result<String, anyhow::Error> {
+    let user = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    let user = UserId(user.parse::<u64>()?);
+    let mut ban = UserBan::Both;
+    let mut purge = false;
+    for arg in &args[1..] {
+        match *arg {
+            "giving" => ban = UserBan::Giving,
+            "receiving" => ban = UserBan::Receiving,
+            "both" => ban = UserBan::Both,
+            "purge" => purge = true,
+            other => bail!(
+                "Unknown option `{}`, try `giving`, `receiving`, `both`, or `purge`",
+                other
+            ),
+        }
+    }
+
+    wrapper
+        .guild(guild)
+        .await
+        .lock()
+        .await
+        .config
+        .banned_users
+        .insert(user, ban);
+    let mut msg = format!("{} is banned from {}", user.mention(), ban.describe());
+    if purge {
+        let purged = wrapper.purge_user(guild, user, ban).await;
+        msg.push_str(&format!(
+            ", and {} of their taters were taken back",
+            purged.taken_back
+        ));
+        if purged.untraced > 0 {
+            msg.push_str(&format!(
+                ". {} of those are from before I kept track of who gave what, \
+                so their givers still have them counted as given",
+                purged.untraced
+            ));
+        }
+    }
+    Ok(msg)
+}
+
+fn unban(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let user = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    let user = UserId(user.parse::<u64>()?);
+    match this.config.banned_users.remove(&user) {
+        Some(_) => Ok(format!(
+            "{}'s taters count again. Any that were taken back stay gone.",
+            user.mention()
+        )),
+        None => Ok(format!("{} wasn't banned", user.mention())),
+    }
+}
+
+fn list_bans(this: &Handler) -> Result<String, anyhow::Error> {
+    if this.config.banned_users.is_empty() {
+        return Ok("Nobody's banned".to_owned());
+    }
+    let mut bans = this.config.banned_users.iter().collect::<Vec<_>>();
+    bans.sort_by_key(|(user, _)| **user);
+    let mut msg = String::from("Banned:");
+    for (user, ban) in bans {
+        msg.push_str(&format!("\n- {} from {}", user.mention(), ban.describe()));
+    }
+    Ok(msg)
+}
+
+/// Post every pin that's in an old pin channel again in the current one
+async fn migrate_pins(
+    args: &[&str],
+    wrapper: &HandlerWrapper,
+    guild: GuildId,
+) -> Result<String, anyhow::Error> {
+    let delete_old = match args.first() {
+        None => false,
+        Some(&"delete_old") => true,
+        Some(other) => bail!("Unknown option `{}`, try `delete_old`", other),
+    };
+    let (queued, unknown) = wrapper.migrate_pins(guild, delete_old).await;
+    if queued == 0 && unknown == 0 {
+        return Ok("All the pins are already in the pin channel".to_owned());
+    }
+    let mut msg = format!(
+        "Moving {} pins to the pin channel, oldest first, about one a second. \
+        Check on it with `rerender status`, or stop with `rerender cancel`.",
+        queued
+    );
+    let nsfw_pins = wrapper.guild(guild).await.lock().await.config.nsfw_pins;
+    if let NsfwPins::Board(board) = nsfw_pins {
+        msg.push_str(&format!(
+            "\nPins of messages from NSFW channels go to {} instead. \
+            Every pin on the main board gets checked for those, but only they move.",
+            board.mention()
+        ));
+    }
+    if delete_old {
+        msg.push_str("\nThe old pins get deleted as they're moved.");
+    }
+    if unknown > 0 {
+        msg.push_str(&format!(
+            "\n{} pins are from before I kept track of channels, so I can't move them.",
+            unknown
+        ));
+    }
+    Ok(msg)
+}
+
+/// Send a preview of a pin to the channel the command was typed in
+async fn preview_pin(
+    args: &[&str],
+    wrapper: &HandlerWrapper,
+    ctx: &Context,
+    guild: GuildId,
+    message: &Message,
+) -> Result<String, anyhow::Error> {
+    let target = match args.first() {
+        Some(id) => MessageId(id.parse::<u64>()?),
+        None => message.id,
+    };
+    let discord = SerenityDiscord::new(ctx);
+    let pin = wrapper
+        .preview_pin(&discord, guild, message.channel_id, target)
+        .await?;
+    discord.send_pin(message.channel_id, &pin).await?;
+    Ok(String::new())
+}
+
+/// Get the channel and message out of a link like
+/// `https://discord.com/channels/<guild>/<channel>/<message>`, checking it's in this guild
+fn parse_message_link(link: &str, guild: GuildId) -> Result<(ChannelId, MessageId), anyhow::Error> {
+    let not_a_link = || anyhow!("`{}` isn't a message link", link);
+    // Discord wraps links in <> to stop them embedding
+    let link = link.trim_start_matches('<').trim_end_matches('>');
+    let ids = link.split("/channels/").nth(1).ok_or_else(not_a_link)?;
+    let ids = ids
+        .split('/')
+        .map(|id| id.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| not_a_link())?;
+    match ids[..] {
+        [link_guild, channel, message] if link_guild == guild.0 => {
+            Ok((ChannelId(channel), MessageId(message)))
+        }
+        [_, _, _] => bail!("That message isn't in this server"),
+        _ => Err(not_a_link()),
+    }
+}
+
+/// Force, block, or lock a message's pin, for the moderation commands
+async fn pin_command<D: Discord + ?Sized>(
+    moderation: Moderation,
+    args: &[&str],
+    wrapper: &HandlerWrapper,
+    discord: &D,
+    guild: GuildId,
+) -> Result<String, anyhow::Error> {
+    let link = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (a message link expected)"))?;
+    let (channel, message) = parse_message_link(link, guild)?;
+    let key = PinKey {
+        guild,
+        channel,
+        message,
+    };
+    let count = wrapper.moderate_pin(discord, key, moderation).await?;
+    Ok(match moderation {
+        Moderation::Force => "Pinning it, however many taters it has".to_owned(),
+        Moderation::Unpin => {
+            "Taking it down, and it won't be pinned again. `allow_pin` undoes that.".to_owned()
+        }
+        Moderation::Allow => "Its taters decide whether it's pinned again".to_owned(),
+        Moderation::Lock => format!("Locked it at {} taters", count),
+        Moderation::Unlock => format!(
+            "Unlocked it at {} taters. Any added or taken off while it was locked weren't counted.",
+            count
+        ),
+    })
+}
+
+/// What doesn't add up in the guild's counts, and the latest changes that didn't
+fn diagnostics(this: &Handler) -> Result<String, anyhow::Error> {
+    const MAX_LINES: usize = 10;
+
+    let problems = this.problems();
+    let mut msg = if problems.is_empty() {
+        "Everything adds up!".to_owned()
+    } else {
+        format!("{} things don't add up:", problems.len())
+    };
+    let problems = problems
+        .iter()
+        .map(|problem| format!("- {}", problem))
+        .collect::<Vec<_>>();
+    // Leave half the message for the anomalies
+    push_list(&mut msg, &problems, MAX_LINES, MAX_MESSAGE_LEN / 2);
+
+    if this.anomalies.is_empty() {
+        msg.push_str("\nNo taters have been given or taken back oddly since I started.");
+    } else {
+        msg.push_str(&format!(
+            "\n{} odd tater changes since I started, latest first:",
+            this.anomalies.len()
+        ));
+        let anomalies = this
+            .anomalies
+            .iter()
+            .rev()
+            .map(|anomaly| format!("- {}", anomaly))
+            .collect::<Vec<_>>();
+        push_list(&mut msg, &anomalies, MAX_LINES, MAX_MESSAGE_LEN);
+    }
+    Ok(msg)
+}
+
+fn vote_rings(this: &Handler) -> Result<String, anyhow::Error> {
+    const MAX_LINES: usize = 10;
+
+    let findings = this.vote_ring_report();
+    if findings.is_empty() {
+        return Ok("Nobody's taters look suspicious".to_owned());
+    }
+    let mut msg = format!("{} things look suspicious:", findings.len());
+    let findings = findings
+        .iter()
+        .enumerate()
+        .map(|(idx, finding)| format!("{}. {}", idx + 1, finding))
+        .collect::<Vec<_>>();
+    push_list(&mut msg, &findings, MAX_LINES, MAX_MESSAGE_LEN);
+    Ok(msg)
+}
+
+fn pair_cap(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let cap = args
+        .first()
+        .ok_or_else(|| anyhow!("Not enough arguments (1 expected)"))?;
+    if *cap == "off" {
+        this.config.pair_cap = None;
+        return Ok("Anyone's taters count for anyone else, however many there are".to_owned());
+    }
+    let cap = cap.parse::<u64>()?;
+    if cap == 0 {
+        bail!("The cap has to be at least 1, or `off`");
+    }
+    this.config.pair_cap = Some(cap);
+    Ok(format!(
+        "Only {} taters from one person will count for any one other person. \
+        Taters already given stay counted.",
+        cap
+    ))
+}
+
+/// `bots` on its own shows which bots and webhooks taters count for, otherwise it changes that
+fn bots(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    let policy = &mut this.config.bot_policy;
+    let value = args.get(1).copied();
+    let on_off = || match value {
+        Some("on") => Ok(true),
+        Some("off") => Ok(false),
+        _ => Err(anyhow!("Expected `on` or `off`")),
+    };
+    match args.first() {
+        None => {}
+        Some(&"messages") => policy.bot_messages = on_off()?,
+        Some(&"reactions") => policy.bot_reactions = on_off()?,
+        Some(&"webhooks") => {
+            policy.webhook_messages = match value {
+                Some("ignore") => WebhookMessages::Ignore,
+                Some("count") => WebhookMessages::Count,
+                Some("credit_proxies") => WebhookMessages::CreditProxies,
+                _ => bail!("Expected `ignore`, `count`, or `credit_proxies`"),
+            }
+        }
+        Some(other) => bail!(
+            "Unknown option `{}`, try `messages`, `reactions`, or `webhooks`",
+            other
+        ),
+    }
+
+    let yes_no = |yes: bool| if yes { "Yes" } else { "No" };
+    Ok(format!(
+        "- Messages from other bots can get taters: {}\n\
+        - Taters from bots count: {}\n\
+        - Messages from webhooks: {}",
+        yes_no(policy.bot_messages),
+        yes_no(policy.bot_reactions),
+        match policy.webhook_messages {
+            WebhookMessages::Ignore => "can't get taters",
+            WebhookMessages::Count => "count like anyone else's",
+            WebhookMessages::CreditProxies =>
+                "count, and PluralKit's go to the account behind them",
+        }
+    ))
+}
+
+/// `max_message_age` on its own shows how old messages can be before new taters on them
+/// are ignored or don't pin them, otherwise it changes that
+fn max_message_age(args: &[&str], this: &mut Handler) -> Result<String, anyhow::Error> {
+    match args.first() {
+        None => {}
+        Some(&"off") => this.config.max_message_age_hours = None,
+        Some(time) => {
+            let hours = parse_hours(time)?;
+            if hours == 0 {
+                bail!("Give a time like `30d`, or `off`");
+            }
+            this.config.max_message_age_hours = Some(hours);
+        }
+    }
+    match args.get(1) {
+        None => {}
+        Some(&"ignore") => this.config.old_messages = OldMessages::Ignore,
+        Some(&"no_pin") => this.config.old_messages = OldMessages::NoPin,
+        Some(other) => bail!("Expected `ignore` or `no_pin`, not `{}`", other),
+    }
+
+    let hours = match this.config.max_message_age_hours {
+        Some(it) => it,
+        None => return Ok("Messages of any age can get taters and be pinned".to_owned()),
+    };
+    let what = match this.config.old_messages {
+        OldMessages::Ignore => "don't count",
+        OldMessages::NoPin => "count, but won't pin it if it isn't already",
+    };
+    Ok(format!(
+        "New taters on messages older than {} {}",
+        format_hours(hours),
+        what
+    ))
+}
+
+/// Discord won't send a message with more characters than this
+const MAX_MESSAGE_LEN: usize = 2000;
+
+/// Add `lines` to the end of `msg`, one per line, as long as there are at most `max_lines`
+/// and `msg` stays within `max_len` characters. Then say how many didn't make it.
+fn push_list(msg: &mut String, lines: &[String], max_lines: usize, max_len: usize) {
+    // Enough room for the last line, whatever's left out
+    const ROOM_FOR_MORE: usize = 32;
+    // A line longer than this gets cut short, so one long one can't crowd out the rest
+    const MAX_LINE_LEN: usize = 300;
+
+    let mut len = msg.chars().count();
+    for (idx, line) in lines.iter().enumerate() {
+        let line = match line.char_indices().nth(MAX_LINE_LEN) {
+            Some((cut, _)) => format!("{}…", &line[..cut]),
+            None => line.clone(),
+        };
+        let line_len = line.chars().count() + 1;
+        let left = lines.len() - idx;
+        let room_for_more = if left > 1 { ROOM_FOR_MORE } else { 0 };
+        if idx == max_lines || len + line_len + room_for_more > max_len {
+            msg.push_str(&format!("\n...and {} more", left));
+            return;
+        }
+        msg.push('\n');
+        msg.push_str(&line);
+        len += line_len;
+    }
+}
+
+/// The admin commands, by topic, along with what each topic covers.
+/// Each topic is sent as its own message, so each has to fit in one.
+const ADMIN_HELP: [(&str, &str, &str); 4] = [
+    (
+        "setup",
+        "the pin channel, potato, threshold, blacklist, admins, and command groups",
+        r"- `set_pin_channel <channel_id>`: Set the channel that pinned messages to go, and adds it to the potato blacklist.
+- `set_potato <emoji>`: Set the given emoji to be the operative one.
+- `set_threshold <number>`: Set how many potatoes have to be on a message before it is pinned.
+- `blacklist <channel_id>`: Make the channel no longer eligible for pinning messages, regardless of potato count.
+- `unblacklist <channel_id>`: Unblacklist this channel so messages from it can be pinned again.
+- `show_blacklist`: Show which channels are ineligible for pinning messages.
+- `admin <user_id>`: Let this user access this bot's admin commands on this server.
+- `unadmin <user_id>`: Stops this user from being an admin on this server.
+- `list_admins`: Print a list of admins.
+- `enable <group>`: Turn on a group of optional commands: `stats`, `exports`, or `fun`.
+- `disable <group>`: Turn off a group of optional commands.
+- `command_groups`: Show which groups of optional commands are on.
+- `save`: Flush any in-memory state to disk.",
+    ),
+    (
+        "pins",
+        "how pins look, where they go, and redoing them",
+        r"- `show_replies <on|off>`: Whether pins of replies show the message they replied to. On by default.
+- `template`: Show how pins are laid out, and the placeholders they can use.
+- `template <header|jump|footer> <text>`: Change part of the pin layout. `jump` and `footer` can be `off`, and can't use the placeholders that change.
+- `template author <on|off>`: Whether pins show who sent the message.
+- `template reset`: Go back to the standard pin layout.
+- `pin_mode <embed|webhook>`: Post pins as embeds, or through a webhook so they look like the original author sent them. Pins already posted stay as they are.
+- `deleted_pins <clear|recreate>`: What to do when someone deletes a pin by hand: keep the message off the board like `unpin` (the default), or post the pin again.
+- `nsfw_pins <allow|never|spoiler|board <channel_id>>`: What to do with pins of messages from NSFW channels: pin them like any other, never pin them, hide them behind spoilers, or pin them in a board of their own.
+- `rerender`: Redo every pin with the current medals, template, and settings, including `nsfw_pins`. `rerender status` shows how many are left, and `rerender cancel` stops.
+- `migrate_pins [delete_old]`: After changing the pin channel, post the pins from the old one again in the new one, oldest first. With `delete_old`, the old pins get deleted.
+- `preview_pin [message_id]`: Show what a pin of a message in this channel would look like. Defaults to this message.",
+    ),
+    (
+        "moderation",
+        "pinning or unpinning messages by hand, bans, and checking the counts",
+        r"- `force_pin <message link>`: Pin a message, however many taters it has.
+- `unpin <message link>`: Take a message's pin down, and never pin it again.
+- `allow_pin <message link>`: Undo `force_pin` or `unpin`, so the message's taters decide again.
+- `lock <message link>`: Freeze a message's tater count, so taters added or taken off aren't counted. `unlock` undoes it.
+- `ban <user_id> [giving|receiving|both] [purge]`: Stop someone's taters counting, both ways unless you say. With `purge`, the taters they already gave or got are taken back too.
+- `unban <user_id>`: Let someone's taters count again.
+- `bans`: List who's banned.
+- `diagnostics`: List anything in this server's tater counts that doesn't add up, and the latest taters given or taken back in ways that didn't make sense.",
+    ),
+    (
+        "counting",
+        "whose taters count, and on which messages",
+        r"- `require`: Show what someone needs before their taters count.
+- `require <account_age|member_age> <time|off>`: How old someone's account has to be, or how long they have to have been here, like `3d` or `12h`.
+- `require <role|not_role> <role_id|off>`: A role givers need, or a role that stops their taters counting.
+- `mod_log <channel_id|off>`: Where to note taters that don't count.
+- `vote_rings`: List people who seem to be tatering each other to get on the board.
+- `pair_cap <count|off>`: The most taters from one person that count for any other one person.
+- `bots`: Show which bots and webhooks taters count for.
+- `bots <messages|reactions> <on|off>`: Whether messages from other bots can get taters, and whether taters from bots count.
+- `bots webhooks <ignore|count|credit_proxies>`: What to do with messages sent through webhooks. `credit_proxies` gives PluralKit's taters to the account behind them.
+- `max_message_age [time|off] [ignore|no_pin]`: How old a message can be, like `30d`, before new taters on it are ignored, or counted without pinning it.",
+    ),
+];
+
+/// `help` on its own lists the topics, and `help <topic>` shows the admin commands for one
+fn admin_help(args: &[&str]) -> Result<String, anyhow::Error> {
+    match args.first() {
+        None => {
+            let mut msg = String::from(
+                "You're an admin! The admin commands are split up by topic, see them with `help <topic>`:",
+            );
+            for (topic, what, _) in &ADMIN_HELP {
+                msg.push_str(&format!("\n- `{}`: {}", topic, what));
+            }
+            msg.push_str(
+                "\nPeople with any role with an Administrator privilege are always admins of this bot.",
+            );
+            Ok(msg)
+        }
+        Some(asked) => match ADMIN_HELP.iter().find(|(topic, _, _)| topic == asked) {
+            Some((topic, _, commands)) => {
+                Ok(format!("Admin commands for {}:\n{}", topic, commands))
+            }
+            None => Err(anyhow!(
+                "There's no help on `{}`, try one of: {}",
+                asked,
+                ADMIN_HELP
+                    .iter()
+                    .map(|(topic, _, _)| *topic)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        },
+    }
+}
+
+fn list_admins(this: &Handler) -> Result<String, anyhow::Error> {
     let mut msg = String::from("Admins:");
     for id in &this.config.admins {
         msg.push_str("\n- ");
@@ -237,6 +1039,103 @@ async fn list_admins(this: &mut Handler) -> Result<String, anyhow::Error> {
     Ok(msg)
 }
 
+/// Run one of the admin commands that only touch the config.
+/// Returns None if `cmd` isn't one of them.
+fn run_config_command(
+    cmd: &str,
+    args: &[&str],
+    this: &mut Handler,
+) -> Option<Result<String, anyhow::Error>> {
+    let res = match cmd {
+        "set_pin_channel" => set_pin_channel(args, this),
+        "set_threshold" => set_threshold(args, this),
+        "blacklist" => blacklist(args, this),
+        "unblacklist" => unblacklist(args, this),
+        "show_blacklist" => Ok(this
+            .config
+            .blacklisted_channels
+            .iter()
+            .map(|c| format!("- {}", c.mention()))
+            .collect::<Vec<_>>()
+            .join("\n")),
+        "set_potato" => set_potato(args, this),
+        "admin" => admin(args, this),
+        "unadmin" => unadmin(args, this),
+        "enable" => enable(args, this),
+        "disable" => disable(args, this),
+        "command_groups" => list_command_groups(this),
+        "show_replies" => show_replies(args, this),
+        "template" => template(args, this),
+        "pin_mode" => pin_mode(args, this),
+        "deleted_pins" => deleted_pins(args, this),
+        "nsfw_pins" => nsfw_pins(args, this),
+        "diagnostics" => diagnostics(this),
+        "require" => require(args, this),
+        "mod_log" => mod_log(args, this),
+        "vote_rings" => vote_rings(this),
+        "pair_cap" => pair_cap(args, this),
+        "unban" => unban(args, this),
+        "bans" => list_bans(this),
+        "bots" => bots(args, this),
+        "max_message_age" => max_message_age(args, this),
+        _ => return None,
+    };
+    Some(res)
+}
+
+impl HandlerWrapper {
+    /// Run a config command on a guild as if an admin had typed it,
+    /// without the trigger word
+    pub async fn run_config_command(
+        &self,
+        guild: GuildId,
+        line: &str,
+    ) -> Result<String, anyhow::Error> {
+        let split = line.split_whitespace().collect::<Vec<_>>();
+        let (cmd, args) = split
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty command"))?;
+
+        match *cmd {
+            "rerender" => return rerender(args, self, guild).await,
+            "migrate_pins" => return migrate_pins(args, self, guild).await,
+            "ban" => return ban(args, self, guild).await,
+            _ => {}
+        }
+        let state = self.guild(guild).await;
+        let mut this = state.lock().await;
+        run_config_command(cmd, args, &mut this)
+            .unwrap_or_else(|| Err(anyhow!("Unknown config command `{}`", cmd)))
+    }
+
+    /// Run one of the commands that moderate a pin, like `unpin <message link>`,
+    /// without the trigger word. Returns None if `line` isn't one of them.
+    pub async fn run_pin_command<D: Discord + ?Sized>(
+        &self,
+        discord: &D,
+        guild: GuildId,
+        line: &str,
+    ) -> Option<Result<String, anyhow::Error>> {
+        let split = line.split_whitespace().collect::<Vec<_>>();
+        let (cmd, args) = split.split_first()?;
+        let moderation = moderation_of(cmd)?;
+        Some(pin_command(moderation, args, self, discord, guild).await)
+    }
+
+    /// Get the title, body, and footer of a page of a guild's leaderboard
+    pub async fn leaderboard(
+        &self,
+        guild: GuildId,
+        leaderboard: &str,
+        page_num: usize,
+        asker: UserId,
+    ) -> Result<(String, String, String), anyhow::Error> {
+        let state = self.guild(guild).await;
+        let this = state.lock().await;
+        generate_leaderboard(leaderboard, page_num, &this, asker)
+    }
+}
+
 pub async fn handle_slash_command(
     wrapper: &HandlerWrapper,
     ctx: Context,
@@ -252,24 +1151,42 @@ pub async fn handle_slash_command(
         .map(|m| m.user.id)
         .ok_or_else(|| anyhow!("no member"))?;
 
-    let mut handlers = wrapper.handlers.lock().await;
-    let handler = handlers.entry(guild_id).or_insert_with(Handler::new);
+    let state = wrapper.guild(guild_id).await;
 
     let data = interaction
         .data
         .as_ref()
         .expect("Caller checked for slash command, so this must be here");
     log::debug!("Slash command id {}, name {}", data.id, data.name);
+    let reply = |content: String| {
+        interaction.create_interaction_response(&ctx.http, |r| {
+            r.interaction_response_data(|d| d.content(content))
+        })
+    };
+    let disabled = disabled_group(&*state.lock().await, &data.name);
+    if let Some(group) = disabled {
+        // They can still be around if commands are registered globally
+        reply(format!(
+            "The {} commands are turned off in this server",
+            group
+        ))
+        .await?;
+        return Ok(());
+    }
+
     match data.name.as_str() {
         "receivers" | "givers" => {
             let value = data
                 .options
-                .get(0)
-                .map(|o| o.value.as_ref().map(|v| v.as_u64()).flatten())
-                .flatten()
+                .first()
+                .and_then(|o| o.value.as_ref().and_then(|v| v.as_u64()))
                 .unwrap_or(1);
-            let (title, description, footer) =
-                generate_leaderboard(data.name.as_str(), value as usize, handler, user_id).await?;
+            let (title, description, footer) = generate_leaderboard(
+                data.name.as_str(),
+                value as usize,
+                &*state.lock().await,
+                user_id,
+            )?;
             interaction
                 .create_interaction_response(&ctx.http, |r| {
                     r.interaction_response_data(|d| {
@@ -283,6 +1200,11 @@ pub async fn handle_slash_command(
                 .await?;
             Ok(())
         }
+        "random_pin" => {
+            let content = random_pin(&*state.lock().await, guild_id);
+            reply(content).await?;
+            Ok(())
+        }
         _ => Err(anyhow!("Unknown command")),
     }
 }
@@ -297,10 +1219,16 @@ pub async fn handle_commands(
         Some(it) => it,
         None => return Ok(()),
     };
-    let mut handlers = wrapper.handlers.lock().await;
-    let this = handlers.entry(guild_id).or_insert_with(Handler::new);
+    let state = wrapper.guild(guild_id).await;
 
-    if message.author.id == uid || !message.content.starts_with(&this.config.trigger_word) {
+    let (triggered, listed_admin) = {
+        let this = state.lock().await;
+        (
+            message.content.starts_with(&this.config.trigger_word),
+            this.config.admins.contains(&message.author.id),
+        )
+    };
+    if message.author.id == uid || !triggered {
         return Ok(());
     }
 
@@ -309,7 +1237,7 @@ pub async fn handle_commands(
         Some(it) => it,
         None => return Ok(()),
     };
-    let is_admin = this.config.admins.contains(&message.author.id)
+    let is_admin = listed_admin
         || match guild
             .member(&ctx.http, message.author.id)
             .await?
@@ -331,51 +1259,48 @@ pub async fn handle_commands(
 
     let res = match cmd {
         "help" => {
-            const ADMIN_HELP: &str = r"You're an admin! Here's the admin commands:
-- `set_pin_channel <channel_id>`: Set the channel that pinned messages to go, and adds it to the potato blacklist.
-- `set_potato <emoji>`: Set the given emoji to be the operative one.
-- `set_threshold <number>`: Set how many potatoes have to be on a message before it is pinned.
-- `blacklist <channel_id>`: Make the channel no longer eligible for pinning messages, regardless of potato count.
-- `unblacklist <channel_id>`: Unblacklist this channel so messages from it can be pinned again.
-- `show_blacklist`: Show which channels are ineligible for pinning messages.
-- `admin <user_id>`: Let this user access this bot's admin commands on this server.
-- `unadmin <user_id>`: Stops this user from being an admin on this server.
-- `list_admins`: Print a list of admins.
-- `save`: Flush any in-memory state to disk.
-People with any role with an Administrator privilege are always admins of this bot.";
             if is_admin {
-                Ok(ADMIN_HELP.to_owned())
+                admin_help(args)
             } else {
                 Ok(String::new())
             }
         }
-        "csv" if is_admin => generate_csv(args, this, ctx, message).await,
-        "set_pin_channel" if is_admin => set_pin_channel(args, this),
-        "set_threshold" if is_admin => set_threshold(args, this),
-        "blacklist" if is_admin => blacklist(args, this),
-        "unblacklist" if is_admin => unblacklist(args, this),
-        "show_blacklist" if is_admin => Ok(this
-            .config
-            .blacklisted_channels
-            .iter()
-            .map(|c| format!("- {}", c.mention()))
-            .collect::<Vec<_>>()
-            .join("\n")),
-        "set_potato" if is_admin => set_potato(args, this),
-        "admin" if is_admin => admin(args, this),
-        "unadmin" if is_admin => unadmin(args, this),
-        "list_admins" if is_admin => list_admins(this).await,
+        "csv" if is_admin => {
+            // Don't hold the lock while uploading
+            let data = {
+                let this = state.lock().await;
+                match disabled_group(&this, cmd) {
+                    Some(group) => Err(anyhow!(
+                        "The {} commands are turned off in this server",
+                        group
+                    )),
+                    None => generate_csv(args, &this),
+                }
+            };
+            match data {
+                Ok(data) => send_csv(data, ctx, message).await,
+                Err(e) => Err(e),
+            }
+        }
+        "list_admins" if is_admin => list_admins(&*state.lock().await),
+        "preview_pin" if is_admin => preview_pin(args, wrapper, ctx, guild_id, message).await,
+        "rerender" if is_admin => rerender(args, wrapper, guild_id).await,
+        "migrate_pins" if is_admin => migrate_pins(args, wrapper, guild_id).await,
+        "ban" if is_admin => ban(args, wrapper, guild_id).await,
         "save" if is_admin => {
             // we only need to save taters cause, as this is an admin command, config is about to get saved
-            let msg = if let Some(id) = message.guild_id {
-                HandlerWrapper::save_server_taters(&wrapper.save_dir_path, &*handlers, id)
-                    .await
-                    .map(|_| String::from("Saved this server's taters!"))
-            } else {
-                Err(anyhow!("There was no guild ID (are you in a PM?)"))
-            };
-            msg
+            HandlerWrapper::save_server_taters(&*wrapper.storage, guild_id, &*state.lock().await)
+                .await
+                .map(|_| String::from("Saved this server's taters!"))
         }
+        _ if is_admin => match moderation_of(cmd) {
+            Some(moderation) => {
+                let discord = SerenityDiscord::new(ctx);
+                pin_command(moderation, args, wrapper, &discord, guild_id).await
+            }
+            None => run_config_command(cmd, args, &mut *state.lock().await)
+                .unwrap_or_else(|| Ok(String::new())),
+        },
         _ => Ok(String::new()),
     };
 
@@ -396,22 +1321,57 @@ People with any role with an Administrator privilege are always admins of this b
     if is_admin {
         // Assume that an admin command means we changed something about the config.
         // This could be done smarter but i don't care
-        if let Some(id) = message.guild_id {
-            HandlerWrapper::save_server_config(&wrapper.save_dir_path, &*handlers, id)
-                .await
-                .map_err(|e| anyhow!(e))?;
-        } else {
-            message
-                .channel_id
-                .say(
-                    &ctx.http,
-                    String::from(
-                        "Unable to save config because there was no guild ID (are you in a PM?)",
-                    ),
-                )
-                .await?;
+        HandlerWrapper::save_server_config(&*wrapper.storage, guild_id, &*state.lock().await)
+            .await
+            .map_err(|e| anyhow!(e))?;
+
+        if cmd == "enable" || cmd == "disable" {
+            wrapper.sync_guild_commands(&ctx.http, guild_id).await?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_help_fits_in_messages() {
+        let mut replies = vec![admin_help(&[]).unwrap()];
+        for (topic, _, _) in &ADMIN_HELP {
+            replies.push(admin_help(&[topic]).unwrap());
+        }
+        for reply in &replies {
+            let len = reply.chars().count();
+            assert!(len <= MAX_MESSAGE_LEN, "{} chars:\n{}", len, reply);
+        }
+    }
+
+    #[test]
+    fn lists_stop_before_the_message_limit() {
+        let lines = (0..50)
+            .map(|idx| format!("- {} {}", idx, "spud ".repeat(100)))
+            .collect::<Vec<_>>();
+        let mut msg = String::from("Lots of lines:");
+        push_list(&mut msg, &lines, 20, MAX_MESSAGE_LEN);
+        assert!(msg.chars().count() <= MAX_MESSAGE_LEN, "{}", msg.len());
+        assert!(msg.ends_with("more"), "{}", msg);
+
+        let mut msg = String::from("A few lines:");
+        push_list(&mut msg, &lines[..3], 2, MAX_MESSAGE_LEN);
+        assert_eq!(msg.lines().count(), 4);
+        assert!(msg.ends_with("...and 1 more"), "{}", msg);
+
+        let mut msg = String::new();
+        push_list(&mut msg, &lines[..2], 10, MAX_MESSAGE_LEN);
+        assert_eq!(msg.lines().filter(|line| line.ends_with('…')).count(), 2);
+    }
+
+    #[test]
+    fn admin_help_rejects_unknown_topics() {
+        let oh_no = admin_help(&["potatoes"]).unwrap_err().to_string();
+        assert!(oh_no.contains("setup"), "{}", oh_no);
+    }
+}
@@ -0,0 +1,469 @@
+//! Looking over and fixing up saved data without running the bot
+
+use std::{
+    collections::{hash_map, HashMap},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context};
+use itertools::Itertools;
+use serde_json::{Map, Value};
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
+
+use super::{
+    config_name, load_guild, pin_queue::PinKey, saved_guild_ids, take_one, taters_name, GuildState,
+    Handler, HandlerWrapper, UserBan,
+};
+use crate::storage::Storage;
+
+impl HandlerWrapper {
+    /// Everything we know about the given guild, or every guild, as one json object keyed by guild ID
+    pub async fn export(&self, guild: Option<GuildId>) -> Result<Value, anyhow::Error> {
+        let guilds = match guild {
+            Some(id) => vec![(id, self.existing_guild(id).await?)],
+            None => self.all_guilds().await,
+        };
+
+        let mut out = Map::new();
+        for (id, state) in guilds {
+            let this = state.lock().await;
+            out.insert(id.to_string(), serde_json::to_value(&*this)?);
+        }
+        Ok(Value::Object(out))
+    }
+
+    /// A guild's config, as json
+    pub async fn config_json(&self, guild: GuildId) -> Result<Value, anyhow::Error> {
+        let state = self.existing_guild(guild).await?;
+        let this = state.lock().await;
+        Ok(serde_json::to_value(&this.config)?)
+    }
+
+    /// Set one field of a guild's config, given as json
+    pub async fn set_config_field(
+        &self,
+        guild: GuildId,
+        field: &str,
+        value: Value,
+    ) -> Result<(), anyhow::Error> {
+        let state = self.existing_guild(guild).await?;
+        let mut this = state.lock().await;
+
+        let mut config = serde_json::to_value(&this.config)?;
+        let fields = config
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("Config isn't a json object"))?;
+        if !fields.contains_key(field) {
+            bail!(
+                "There's no config field `{}`, try one of: {}",
+                field,
+                fields.keys().cloned().collect::<Vec<_>>().join(", ")
+            );
+        }
+        fields.insert(field.to_owned(), value);
+        this.config = serde_json::from_value(config)
+            .with_context(|| format!("That's not a valid `{}`", field))?;
+        Ok(())
+    }
+
+    /// Fold all of `from`'s taters into `into`, then forget about `from`.
+    /// `into` keeps its own config. Messages both know about keep each giver once.
+    /// `into` is saved before `from` is deleted, so a failed save never loses anything.
+    pub async fn merge_guilds(&self, from: GuildId, into: GuildId) -> Result<(), anyhow::Error> {
+        if from == into {
+            bail!("Can't merge a guild into itself");
+        }
+        let from_state = self.existing_guild(from).await?;
+        let into_state = self.existing_guild(into).await?;
+        {
+            let from_this = from_state.lock().await;
+            let mut into_this = into_state.lock().await;
+            // Taters both guilds counted, which mustn't be counted twice
+            let mut got_twice = HashMap::<UserId, u64>::new();
+            let mut given_twice = Vec::new();
+            for (id, msg) in &from_this.tatered_messages {
+                match into_this.tatered_messages.entry(*id) {
+                    hash_map::Entry::Occupied(mut o) => {
+                        let into_msg = o.get_mut();
+                        // Taters from before we kept track of givers, which could be anyone's
+                        let unknown = (into_msg.count.saturating_sub(into_msg.givers.len() as u64))
+                            .max(msg.count.saturating_sub(msg.givers.len() as u64));
+                        for giver in &msg.givers {
+                            if into_msg.givers.contains(giver) {
+                                given_twice.push(*giver);
+                            } else {
+                                into_msg.givers.push(*giver);
+                            }
+                        }
+                        let count = into_msg.givers.len() as u64 + unknown;
+                        *got_twice.entry(into_msg.sender).or_insert(0) += into_msg
+                            .count
+                            .saturating_add(msg.count)
+                            .saturating_sub(count);
+                        into_msg.count = count;
+                    }
+                    hash_map::Entry::Vacant(v) => {
+                        v.insert(msg.clone());
+                    }
+                }
+            }
+            for (user, count) in &from_this.taters_got {
+                let got = into_this.taters_got.entry(*user).or_insert(0);
+                *got = got.saturating_add(*count);
+            }
+            for (user, count) in &from_this.taters_given {
+                let given = into_this.taters_given.entry(*user).or_insert(0);
+                *given = given.saturating_add(*count);
+            }
+            for (user, count) in got_twice {
+                let got = into_this.taters_got.entry(user).or_insert(0);
+                *got = got.saturating_sub(count);
+            }
+            for giver in given_twice {
+                take_one(&mut into_this.taters_given, giver);
+            }
+            into_this.recount_pairs();
+            HandlerWrapper::save_server_config(&*self.storage, into, &into_this)
+                .await
+                .with_context(|| format!("saving guild {}", into))?;
+            HandlerWrapper::save_server_taters(&*self.storage, into, &into_this)
+                .await
+                .with_context(|| format!("saving guild {}", into))?;
+        }
+        self.forget_guild(from).await
+    }
+
+    /// Move the given messages, and the taters their senders got for them, from one guild
+    /// to another. If `into` is new, it starts with a copy of `from`'s config.
+    /// Givers' counts move along with the taters we know they gave. Taters from saves too old
+    /// to say who gave them stay counted as given in `from`.
+    /// Returns how many messages were moved.
+    pub async fn split_guild(
+        &self,
+        from: GuildId,
+        into: GuildId,
+        messages: &[MessageId],
+    ) -> Result<usize, anyhow::Error> {
+        if from == into {
+            bail!("Can't split a guild into itself");
+        }
+        let from_state = self.existing_guild(from).await?;
+        let config = {
+            let from_this = from_state.lock().await;
+            if let Some(id) = messages
+                .iter()
+                .find(|id| !from_this.tatered_messages.contains_key(id))
+            {
+                bail!("Guild {} doesn't know about message {}", from, id);
+            }
+            from_this.config.clone()
+        };
+        let into_state = {
+            let mut guilds = self.guilds.write().await;
+            Arc::clone(guilds.entry(into).or_insert_with(|| {
+                let mut handler = Handler::new();
+                handler.config = config;
+                Arc::new(GuildState::new(handler))
+            }))
+        };
+
+        let mut from_this = from_state.lock().await;
+        let mut into_this = into_state.lock().await;
+        let mut moved = 0;
+        for id in messages {
+            let msg = match from_this.tatered_messages.remove(id) {
+                Some(it) => it,
+                // Listed twice
+                None => continue,
+            };
+            if let Some(got) = from_this.taters_got.get_mut(&msg.sender) {
+                *got = got.saturating_sub(msg.count);
+            }
+            let got = into_this.taters_got.entry(msg.sender).or_insert(0);
+            *got = got.saturating_add(msg.count);
+            for giver in &msg.givers {
+                take_one(&mut from_this.taters_given, *giver);
+                let given = into_this.taters_given.entry(*giver).or_insert(0);
+                *given = given.saturating_add(1);
+            }
+            into_this.tatered_messages.insert(*id, msg);
+            moved += 1;
+        }
+        from_this.recount_pairs();
+        into_this.recount_pairs();
+        Ok(moved)
+    }
+
+    /// Zero out any counts that went below zero, in one guild or all of them.
+    /// Returns a description of each fix.
+    pub async fn fix_counts(&self, guild: Option<GuildId>) -> Result<Vec<String>, anyhow::Error> {
+        let guilds = match guild {
+            Some(id) => vec![(id, self.existing_guild(id).await?)],
+            None => self.all_guilds().await,
+        };
+
+        let mut fixes = Vec::new();
+        for (id, state) in guilds {
+            let mut this = state.lock().await;
+            for (msg_id, msg) in this.tatered_messages.iter_mut() {
+                if looks_negative(msg.count) {
+                    msg.count = 0;
+                    fixes.push(format!("guild {}: reset message {} to 0", id, msg_id));
+                }
+            }
+            for (user, count) in this.taters_got.iter_mut() {
+                if looks_negative(*count) {
+                    *count = 0;
+                    fixes.push(format!(
+                        "guild {}: reset taters received by {} to 0",
+                        id, user
+                    ));
+                }
+            }
+            for (user, count) in this.taters_given.iter_mut() {
+                if looks_negative(*count) {
+                    *count = 0;
+                    fixes.push(format!("guild {}: reset taters given by {} to 0", id, user));
+                }
+            }
+        }
+        Ok(fixes)
+    }
+
+    /// Take back every tater `user` gave, every tater they got, or both, depending on `ban`,
+    /// and queue the pins that changed to catch up
+    pub(super) async fn purge_user(&self, guild: GuildId, user: UserId, ban: UserBan) -> Purged {
+        let state = self.guild(guild).await;
+        let (purged, changed) = state.lock().await.purge_user(user, ban);
+        for key in changed {
+            self.pin_queue.push(PinKey {
+                guild,
+                channel: key.0,
+                message: key.1,
+            });
+        }
+        purged
+    }
+
+    /// A guild we already have data for
+    async fn existing_guild(&self, guild: GuildId) -> Result<Arc<GuildState>, anyhow::Error> {
+        self.guilds
+            .read()
+            .await
+            .get(&guild)
+            .cloned()
+            .ok_or_else(|| anyhow!("There's no data for guild {}", guild))
+    }
+
+    /// Drop a guild's data, both in memory and in storage
+    async fn forget_guild(&self, guild: GuildId) -> Result<(), anyhow::Error> {
+        self.guilds.write().await.remove(&guild);
+        self.storage.remove(&taters_name(guild))?;
+        self.storage.remove(&config_name(guild))?;
+        Ok(())
+    }
+}
+
+/// Counts are only ever taken down after being put up, so anything this big
+/// must have gone below zero
+fn looks_negative(count: u64) -> bool {
+    count > u64::MAX / 2
+}
+
+/// Load every guild in `storage` and check it makes sense.
+/// Returns a description of each problem found.
+pub fn validate_data(storage: &dyn Storage) -> Result<Vec<String>, anyhow::Error> {
+    let mut problems = Vec::new();
+    for id in saved_guild_ids(storage)? {
+        match load_guild(storage, id) {
+            Ok(handler) => problems.extend(
+                handler
+                    .problems()
+                    .into_iter()
+                    .map(|problem| format!("guild {}: {}", id, problem)),
+            ),
+            Err(oh_no) => problems.push(format!("guild {}: {:#}", id, oh_no)),
+        }
+    }
+    Ok(problems)
+}
+
+/// What came of taking back someone's taters
+#[derive(Debug, Default)]
+pub(super) struct Purged {
+    /// How many taters were taken back
+    pub taken_back: u64,
+    /// How many of those were from saves too old to know who gave them,
+    /// so the givers still have them counted as given
+    pub untraced: u64,
+}
+
+impl Handler {
+    /// Take back `user`'s taters, as given by `ban`. Also returns the channel and message
+    /// of each pin that changed.
+    fn purge_user(&mut self, user: UserId, ban: UserBan) -> (Purged, Vec<(ChannelId, MessageId)>) {
+        let mut purged = Purged::default();
+        let mut changed = Vec::new();
+        for (id, msg) in self.tatered_messages.iter_mut() {
+            let before = msg.count;
+            if ban.receiving() && msg.sender == user {
+                // Only the givers we know of can have their taters handed back
+                purged.untraced += msg.count.saturating_sub(msg.givers.len() as u64);
+                for giver in msg.givers.drain(..) {
+                    take_one(&mut self.taters_given, giver);
+                    take_one(&mut self.taters_between, (giver, user));
+                }
+                let got = self.taters_got.entry(user).or_insert(0);
+                *got = got.saturating_sub(msg.count);
+                msg.count = 0;
+            } else if ban.giving() {
+                if let Some(idx) = msg.givers.iter().position(|giver| *giver == user) {
+                    msg.givers.remove(idx);
+                    msg.count = msg.count.saturating_sub(1);
+                    take_one(&mut self.taters_given, user);
+                    take_one(&mut self.taters_got, msg.sender);
+                    take_one(&mut self.taters_between, (user, msg.sender));
+                }
+            }
+            if msg.count == before {
+                continue;
+            }
+            purged.taken_back += before - msg.count;
+            if let (Some(channel), Some(_)) = (msg.channel, msg.pin_id) {
+                changed.push((channel, *id));
+            }
+        }
+        (purged, changed)
+    }
+
+    /// Anything about this guild's data that doesn't add up
+    pub(super) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.config.threshold == 0 {
+            problems.push("the threshold is 0".to_owned());
+        }
+        if self.config.medals.is_empty() {
+            problems.push("there are no medals".to_owned());
+        }
+        let pins = self
+            .tatered_messages
+            .values()
+            .filter(|msg| msg.pin_id.is_some())
+            .count();
+        if pins > 0 && self.config.pin_channel.0 == 0 {
+            problems.push(format!(
+                "{} messages are pinned but there's no pin channel",
+                pins
+            ));
+        }
+
+        let mut on_their_messages = HashMap::new();
+        for (id, msg) in &self.tatered_messages {
+            if looks_negative(msg.count) {
+                problems.push(format!("message {} has a negative count", id));
+            }
+            if msg.givers.len() as u64 > msg.count {
+                problems.push(format!(
+                    "message {} has {} taters but lists {} givers",
+                    id,
+                    msg.count,
+                    msg.givers.len()
+                ));
+            }
+            if msg.givers.contains(&msg.sender) {
+                problems.push(format!("message {} counts a tater from its sender", id));
+            }
+            if msg.givers.iter().unique().count() != msg.givers.len() {
+                problems.push(format!("message {} counts someone's tater twice", id));
+            }
+            let sum = on_their_messages.entry(msg.sender).or_insert(0u64);
+            *sum = sum.wrapping_add(msg.count);
+        }
+        for (user, sum) in on_their_messages {
+            let got = self.taters_got.get(&user).copied().unwrap_or(0);
+            if got < sum {
+                problems.push(format!(
+                    "user {} has {} taters on their messages but only received {}",
+                    user, sum, got
+                ));
+            }
+        }
+        for (user, &count) in &self.taters_got {
+            if looks_negative(count) {
+                problems.push(format!("user {} has received a negative count", user));
+            }
+        }
+        for (user, &count) in &self.taters_given {
+            if looks_negative(count) {
+                problems.push(format!("user {} has given a negative count", user));
+            }
+        }
+
+        let on_messages = self
+            .tatered_messages
+            .values()
+            .fold(0u64, |sum, msg| sum.wrapping_add(msg.count));
+        let got = self
+            .taters_got
+            .values()
+            .fold(0u64, |sum, &count| sum.wrapping_add(count));
+        let given = self
+            .taters_given
+            .values()
+            .fold(0u64, |sum, &count| sum.wrapping_add(count));
+        if got != on_messages {
+            problems.push(format!(
+                "messages have {} taters on them but {} were received",
+                on_messages, got
+            ));
+        }
+        if got != given {
+            problems.push(format!(
+                "{} taters were received but {} were given",
+                got, given
+            ));
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::give;
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn merging_counts_shared_givers_once() {
+        let mut first = Handler::new();
+        give(&mut first, 2, 5, 100);
+        give(&mut first, 3, 5, 100);
+        let mut second = Handler::new();
+        give(&mut second, 3, 5, 100);
+        give(&mut second, 4, 5, 100);
+        give(&mut second, 4, 6, 101);
+
+        let wrapper = HandlerWrapper::new(Arc::new(MemoryStorage::default())).unwrap();
+        {
+            let mut guilds = wrapper.guilds.write().await;
+            guilds.insert(GuildId(1), Arc::new(GuildState::new(first)));
+            guilds.insert(GuildId(2), Arc::new(GuildState::new(second)));
+        }
+        wrapper.merge_guilds(GuildId(2), GuildId(1)).await.unwrap();
+
+        let state = wrapper.existing_guild(GuildId(1)).await.unwrap();
+        let merged = state.lock().await;
+        let msg = &merged.tatered_messages[&MessageId(100)];
+        assert_eq!(msg.givers, vec![UserId(2), UserId(3), UserId(4)]);
+        assert_eq!(msg.count, 3);
+        assert_eq!(merged.taters_got[&UserId(5)], 3);
+        assert_eq!(merged.taters_got[&UserId(6)], 1);
+        assert_eq!(merged.taters_given[&UserId(3)], 1);
+        assert_eq!(merged.taters_given[&UserId(4)], 2);
+        assert_eq!(merged.pair_count(UserId(3), UserId(5)), 1);
+        assert!(merged.problems().is_empty(), "{:?}", merged.problems());
+        assert!(wrapper.existing_guild(GuildId(2)).await.is_err());
+    }
+}
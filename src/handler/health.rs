@@ -0,0 +1,94 @@
+//! Whether the bot is up and working, for supervisors to check on
+
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime},
+};
+
+use serenity::{client::bridge::gateway::event::ShardStageUpdateEvent, gateway::ConnectionStage};
+
+use super::HandlerWrapper;
+
+/// If a guild's lock can't be had in this long, something's stuck holding it
+const LOCK_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the last attempt to save everything went
+#[derive(Debug, Default)]
+pub(super) struct SaveStatus {
+    /// When everything was last saved successfully
+    last_success: Option<SystemTime>,
+    /// Why the most recent save failed, if it did
+    last_error: Option<String>,
+}
+
+/// A snapshot of how the bot's doing
+#[derive(Debug, Clone)]
+pub struct Health {
+    /// `ready` has happened and the gateway hasn't dropped since
+    pub connected: bool,
+    /// We haven't started shutting down
+    pub accepting_events: bool,
+    /// Every guild's lock could be taken in good time
+    pub locks_ok: bool,
+    pub last_save: Option<SystemTime>,
+    /// Why the most recent save failed, if it did
+    pub last_save_error: Option<String>,
+}
+
+impl Health {
+    /// Nothing's stuck or failing
+    pub fn healthy(&self) -> bool {
+        self.locks_ok && self.last_save_error.is_none()
+    }
+
+    /// Ready to handle events
+    pub fn ready(&self) -> bool {
+        self.connected && self.accepting_events
+    }
+}
+
+impl HandlerWrapper {
+    /// Check on how the bot's doing
+    pub async fn health(&self) -> Health {
+        let locks_ok = tokio::time::timeout(LOCK_CHECK_TIMEOUT, async {
+            for (_, state) in self.all_guilds().await {
+                drop(state.lock().await);
+            }
+        })
+        .await
+        .is_ok();
+
+        let save_status = self.save_status.lock().unwrap();
+        Health {
+            connected: self.connected.load(Ordering::SeqCst),
+            accepting_events: !self.is_shutting_down(),
+            locks_ok,
+            last_save: save_status.last_success,
+            last_save_error: save_status.last_error.clone(),
+        }
+    }
+
+    /// Remember how a save of everything went
+    pub(super) fn record_save(&self, result: &Result<(), anyhow::Error>) {
+        let mut save_status = self.save_status.lock().unwrap();
+        match result {
+            Ok(()) => {
+                save_status.last_success = Some(SystemTime::now());
+                save_status.last_error = None;
+            }
+            Err(oh_no) => save_status.last_error = Some(format!("{:#}", oh_no)),
+        }
+    }
+
+    /// Keep track of whether the gateway's connected
+    pub(super) fn on_shard_stage_update(&self, update: &ShardStageUpdateEvent) {
+        log::debug!(
+            "Shard {} went from {} to {}",
+            update.shard_id,
+            update.old,
+            update.new
+        );
+        self.connected
+            .store(update.new == ConnectionStage::Connected, Ordering::SeqCst);
+    }
+}
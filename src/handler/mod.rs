@@ -1,176 +1,615 @@
 mod commands;
+mod data;
+mod health;
+mod pin_queue;
+mod pins;
+mod registration;
+mod requirements;
+mod template;
 mod updates;
+mod vote_rings;
 
 use std::{
-    collections::{hash_map, HashMap, HashSet},
-    fs::File,
-    path::Path,
-    path::PathBuf,
-    sync::Arc,
+    collections::{hash_map, HashMap, HashSet, VecDeque},
+    hash::Hash,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{anyhow, bail, Context as AnyhowContext};
+use anyhow::{anyhow, Context as AnyhowContext};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serenity::{
     async_trait,
+    cache::Cache,
+    client::bridge::gateway::event::ShardStageUpdateEvent,
     model::{
         channel::Message,
         channel::Reaction,
         channel::ReactionType,
         gateway::Ready,
+        guild::Guild,
         id::ChannelId,
         id::GuildId,
         id::MessageId,
-        id::{EmojiId, UserId},
+        id::{EmojiId, UserId, WebhookId},
         interactions::{Interaction, InteractionType},
     },
     prelude::*,
 };
+use tokio::{
+    sync::MutexGuard,
+    time::{Duration, Instant},
+};
 
+use crate::{
+    discord::{Discord, MessageAuthor, PinWebhook, SerenityDiscord},
+    metrics::{Event, METRICS},
+    storage::Storage,
+};
+pub use data::validate_data;
+pub use health::Health;
+use health::SaveStatus;
+use pin_queue::{PinKey, PinQueue, Take};
+pub use registration::{CommandGroup, Registration};
+pub use requirements::{BotPolicy, GiverRequirements, ReactingMember, UserBan, WebhookMessages};
+pub use template::{DeletedPins, NsfwPins, PinMode, PinTemplate};
 use updates::Updates;
+pub use updates::{GuildStats, Schedule};
 
-/// Has an arc-muxed wrapper to the true handlers
+/// Has an arc-muxed wrapper to the true handlers.
+/// Cloning it is cheap, and the clones all share the same state.
+#[derive(Clone)]
 pub struct HandlerWrapper {
-    handlers: Arc<Mutex<HashMap<GuildId, Handler>>>,
-    save_dir_path: PathBuf,
+    /// Each guild gets its own lock, so one slow guild doesn't hold up the rest.
+    /// The outer lock is only held long enough to look up or add a guild.
+    guilds: Arc<RwLock<HashMap<GuildId, Arc<GuildState>>>>,
+    /// Where guild data is saved
+    storage: Arc<dyn Storage>,
+
+    /// Pins waiting to be brought up to date
+    pin_queue: Arc<PinQueue>,
+    /// Whether the pin queue worker has been started.
+    /// `ready` can happen more than once, but we only want one worker.
+    pin_worker_started: Arc<AtomicBool>,
+
+    /// Set once we start shutting down, after which new events are ignored
+    shutting_down: Arc<AtomicBool>,
 
     /// Update info
     updates: Arc<Mutex<Updates>>,
 
+    /// Where slash commands get registered
+    registration: Registration,
+
     bot_user_id: Arc<RwLock<Option<UserId>>>,
+    /// Whether the gateway's connected right now
+    connected: Arc<AtomicBool>,
+    /// How the last save went
+    save_status: Arc<std::sync::Mutex<SaveStatus>>,
 }
 
 impl HandlerWrapper {
-    /// Try to load from the given file, or just create default if it can't
-    pub fn new(save_path: PathBuf) -> Result<Self, anyhow::Error> {
-        let map = match save_path.read_dir() {
-            Ok(dir) => dir
-                // Map to only the file stems
-                .filter_map(|entry| {
-                    let entry = entry.ok()?;
-                    let path = entry.path();
-                    let stem = path.file_stem()?;
-                    let stem = stem.to_string_lossy();
-                    let stem = stem.into_owned();
-                    // Now get out the ID part first
-                    let id_end_idx = stem.find(|c: char| !c.is_numeric())?;
-                    let id = &stem[..id_end_idx];
-                    let id = id.parse::<u64>().ok()?;
-                    Some(id)
-                })
-                // Unique-ify the stems
-                // this is because `xyz_taters.json` and `xyz_config.json` will both be loaded
-                // otherwise we would try to open those twice.
-                // We are collecting to a hashmap, not a vec, so technically we don't *need*
-                // to do this but it probably saves some time
-                .unique()
-                // Now open up the files
-                .filter_map(|id| {
-                    let tater_filepath = format!("{}_taters.json", id);
-                    let tater_file = File::open(save_path.join(tater_filepath)).ok()?;
-                    let config_filepath = format!("{}_config.json", id);
-                    let config_file = File::open(save_path.join(config_filepath)).ok()?;
-
-                    let taters: HandlerButOnlyTaters = serde_json::from_reader(tater_file).ok()?;
-                    let config: Config = serde_json::from_reader(config_file).ok()?;
+    /// Load every guild saved in `storage`. Guilds that can't be loaded are skipped.
+    pub fn new(storage: Arc<dyn Storage>) -> Result<Self, anyhow::Error> {
+        let mut map = HashMap::new();
+        for id in saved_guild_ids(&*storage)? {
+            match load_guild(&*storage, id) {
+                Ok(handler) => {
                     log::info!("Loaded taters and config for guild {}", id);
-                    Some((
-                        GuildId(id),
-                        Handler {
-                            config,
-                            tatered_messages: taters.tatered_messages,
-                            taters_given: taters.taters_given,
-                            taters_got: taters.taters_got,
-                        },
-                    ))
-                })
-                .collect(),
-            Err(e) => {
-                bail!(e);
+                    map.insert(id, Arc::new(GuildState::new(handler)));
+                }
+                Err(oh_no) => log::error!("Couldn't load guild {}, skipping it: {:?}", id, oh_no),
             }
-        };
+        }
         Ok(Self {
-            handlers: Arc::new(Mutex::new(map)),
-            save_dir_path: save_path,
-            updates: Arc::new(Mutex::new(Updates::new())),
+            guilds: Arc::new(RwLock::new(map)),
+            pin_queue: Arc::new(PinQueue::load(Arc::clone(&storage))),
+            pin_worker_started: Arc::new(AtomicBool::new(false)),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            storage,
+            updates: Arc::new(Mutex::new(Updates::new(Schedule::default()))),
+            registration: Registration::Global,
             bot_user_id: Arc::new(RwLock::new(None)),
+            connected: Arc::new(AtomicBool::new(false)),
+            save_status: Arc::new(std::sync::Mutex::new(SaveStatus::default())),
         })
     }
 
-    /// Save the taters only to json
-    async fn save_taters<P: AsRef<Path>>(
-        path: P,
-        handlers: &HashMap<GuildId, Handler>,
-    ) -> Result<(), anyhow::Error> {
-        for (&id, _) in handlers.iter() {
-            HandlerWrapper::save_server_taters(&path, &handlers, id).await?;
+    /// Use a different schedule for the periodic jobs
+    pub fn with_schedule(self, schedule: Schedule) -> Self {
+        Self {
+            updates: Arc::new(Mutex::new(Updates::new(schedule))),
+            ..self
         }
+    }
 
-        Ok(())
+    /// Get a guild's state, making a fresh one if we haven't seen it before
+    async fn guild(&self, guild: GuildId) -> Arc<GuildState> {
+        if let Some(state) = self.guilds.read().await.get(&guild) {
+            return Arc::clone(state);
+        }
+        let mut guilds = self.guilds.write().await;
+        Arc::clone(
+            guilds
+                .entry(guild)
+                .or_insert_with(|| Arc::new(GuildState::new(Handler::new()))),
+        )
     }
 
-    /// Save one server's taters to json
-    async fn save_server_taters<P: AsRef<Path>>(
-        path: P,
-        handlers: &HashMap<GuildId, Handler>,
-        guild: GuildId,
-    ) -> Result<(), anyhow::Error> {
-        let handler = handlers
-            .get(&guild)
-            .ok_or_else(|| anyhow!("Guild id {} didn't exist somehow", guild.0))?;
-        let file = File::create(path.as_ref().join(format!("{}_taters.json", guild)))?;
+    /// Snapshot of every guild's state, so they can be visited without
+    /// holding the guild map locked
+    async fn all_guilds(&self) -> Vec<(GuildId, Arc<GuildState>)> {
+        let guilds = self.guilds.read().await;
+        guilds
+            .iter()
+            .map(|(&id, state)| (id, Arc::clone(state)))
+            .collect()
+    }
 
+    /// Save one server's taters to json, returning how many bytes were written
+    async fn save_server_taters(
+        storage: &dyn Storage,
+        guild: GuildId,
+        handler: &Handler,
+    ) -> Result<usize, anyhow::Error> {
         // Make the wrapper struct
         let hbot = HandlerButOnlyTatersRef {
             tatered_messages: &handler.tatered_messages,
             taters_given: &handler.taters_given,
             taters_got: &handler.taters_got,
         };
-        serde_json::to_writer(file, &hbot)?;
+        let json = serde_json::to_string(&hbot)?;
+        storage.write(&taters_name(guild), &json)?;
         log::debug!("Saved taters for guild {:?}", guild);
-        Ok(())
+        Ok(json.len())
     }
 
-    /// Save the configuration only to json
-    async fn save_config<P: AsRef<Path>>(
-        path: P,
-        handlers: &HashMap<GuildId, Handler>,
-    ) -> Result<(), anyhow::Error> {
-        for (&id, _) in handlers.iter() {
-            HandlerWrapper::save_server_config(&path, &handlers, id).await?
-        }
-        Ok(())
-    }
-
-    /// Save one server's config to json
-    async fn save_server_config<P: AsRef<Path>>(
-        path: P,
-        handlers: &HashMap<GuildId, Handler>,
+    /// Save one server's config to json, returning how many bytes were written
+    async fn save_server_config(
+        storage: &dyn Storage,
         guild: GuildId,
-    ) -> Result<(), anyhow::Error> {
-        let handler = handlers
-            .get(&guild)
-            .ok_or_else(|| anyhow!("Guild id {} didn't exist somehow", guild.0))?;
-        let file = File::create(path.as_ref().join(format!("{}_config.json", guild)))?;
-        serde_json::to_writer(file, &handler.config)?;
+        handler: &Handler,
+    ) -> Result<usize, anyhow::Error> {
+        let json = serde_json::to_string(&handler.config)?;
+        storage.write(&config_name(guild), &json)?;
         log::debug!("Saved config for guild {:?}", guild);
-        Ok(())
+        Ok(json.len())
     }
 
     /// Save EVERYTHING
-    async fn save_all<P: AsRef<Path>>(
-        path: P,
-        handlers: &HashMap<GuildId, Handler>,
+    pub async fn save_all(&self) -> Result<(), anyhow::Error> {
+        let res = self.save_everything(&std::sync::Mutex::default()).await;
+        self.record_save(&res);
+        res
+    }
+
+    /// Save everything, unless it takes longer than `limit`, so a stuck write can't hold
+    /// up shutting down. Returns the guilds that didn't get saved if it ran out of time.
+    pub async fn save_all_within(&self, limit: Duration) -> Result<Vec<GuildId>, anyhow::Error> {
+        let saved = std::sync::Mutex::default();
+        if let Ok(res) = tokio::time::timeout(limit, self.save_everything(&saved)).await {
+            self.record_save(&res);
+            return res.map(|()| Vec::new());
+        }
+        self.record_save(&Err(anyhow!("ran out of time saving")));
+        let saved: HashSet<GuildId> = saved.into_inner().unwrap();
+        Ok(self
+            .all_guilds()
+            .await
+            .into_iter()
+            .map(|(id, _)| id)
+            .filter(|id| !saved.contains(id))
+            .sorted()
+            .collect())
+    }
+
+    /// Save every guild, adding each to `saved` once it's done
+    async fn save_everything(
+        &self,
+        saved: &std::sync::Mutex<HashSet<GuildId>>,
     ) -> Result<(), anyhow::Error> {
-        HandlerWrapper::save_config(&path, handlers).await?;
-        HandlerWrapper::save_taters(&path, handlers).await?;
+        let start = Instant::now();
+        let mut bytes = 0;
+        for (id, state) in self.all_guilds().await {
+            let this = state.lock().await;
+            bytes += HandlerWrapper::save_server_config(&*self.storage, id, &this).await?;
+            bytes += HandlerWrapper::save_server_taters(&*self.storage, id, &this).await?;
+            saved.lock().unwrap().insert(id);
+        }
+        METRICS.saved(start.elapsed(), bytes as u64);
         Ok(())
     }
 
-    async fn bot_uid(&self) -> UserId {
-        let g = self.bot_user_id.read().await;
-        g.expect("Asking for bot UID before Ready event has been received")
+    /// Our own user ID, or None if `ready` hasn't told us yet
+    async fn bot_uid(&self) -> Option<UserId> {
+        *self.bot_user_id.read().await
+    }
+
+    /// Remember our own user ID, once the gateway tells us what it is
+    pub async fn set_bot_uid(&self, uid: UserId) {
+        let mut g = self.bot_user_id.write().await;
+        *g = Some(uid);
+    }
+
+    /// Start working through the pin queue in the background, if that isn't happening already
+    pub fn start_pin_worker(&self, discord: Arc<dyn Discord>) {
+        if self.pin_worker_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        tokio::spawn(pin_queue::run(
+            Arc::clone(&self.pin_queue),
+            Arc::clone(&self.guilds),
+            discord,
+        ));
+    }
+
+    /// Stop handling new events, so nothing changes while we flush and save on the way out
+    pub fn stop_accepting_events(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Bring every queued pin's count up to date right now, without waiting for them to come due.
+    /// Pins being redone are left for next time.
+    pub async fn flush_pins<D: Discord + ?Sized>(&self, discord: &D) {
+        pin_queue::process(&self.pin_queue, &self.guilds, discord, Take::Updates).await;
+    }
+
+    /// Work through the whole pin queue right now, redoing pins and all
+    pub async fn drain_pins<D: Discord + ?Sized>(&self, discord: &D) {
+        pin_queue::process(&self.pin_queue, &self.guilds, discord, Take::Everything).await;
+    }
+
+    /// IDs of every guild we have data for, in order
+    pub async fn guild_ids(&self) -> Vec<GuildId> {
+        let guilds = self.guilds.read().await;
+        guilds.keys().copied().sorted().collect()
+    }
+
+    /// The emoji a guild counts as a potato
+    pub async fn tater_emoji(&self, guild: GuildId) -> ReactionType {
+        let state = self.guild(guild).await;
+        let this = state.lock().await;
+        this.config.tater_emoji.clone()
+    }
+
+    /// Someone reacted to a message
+    pub async fn on_reaction_add<D: Discord + ?Sized>(
+        &self,
+        discord: &D,
+        reaction: &TaterReaction,
+    ) {
+        let state = self.guild(reaction.guild_id).await;
+        if !state.lock().await.counts_reaction(reaction) {
+            return;
+        }
+
+        log::trace!(
+            "tater added by {:?} to message {:?}",
+            reaction.user_id,
+            reaction.message_id
+        );
+        let bot_uid = match self.bot_uid().await {
+            Some(it) => it,
+            None => {
+                log::warn!(
+                    "`reaction_add` before `ready`, so we can't tell our own messages apart"
+                );
+                return;
+            }
+        };
+        match state.do_add_tater(discord, reaction, bot_uid).await {
+            Ok(true) => {
+                METRICS.tater_added();
+                self.pin_queue.push(PinKey::of(reaction));
+            }
+            Ok(false) => {}
+            Err(oh_no) => log::error!("`reaction_add`: {:?}", oh_no),
+        }
+    }
+
+    /// Someone took a reaction off a message
+    pub async fn on_reaction_remove(&self, reaction: &TaterReaction) {
+        let state = self.guild(reaction.guild_id).await;
+        if !state.lock().await.counts_reaction(reaction) {
+            return;
+        }
+
+        log::trace!(
+            "tater removed by {:?} from message {:?}",
+            reaction.user_id,
+            reaction.message_id
+        );
+        if state.lock().await.remove_tater(reaction) {
+            METRICS.tater_removed();
+            self.pin_queue.push(PinKey::of(reaction));
+        }
+    }
+}
+
+/// Name of the blob a guild's taters are saved in
+fn taters_name(guild: GuildId) -> String {
+    format!("{}_taters.json", guild)
+}
+
+/// Name of the blob a guild's config is saved in
+fn config_name(guild: GuildId) -> String {
+    format!("{}_config.json", guild)
+}
+
+/// IDs of every guild with anything saved
+fn saved_guild_ids(storage: &dyn Storage) -> Result<Vec<GuildId>, anyhow::Error> {
+    let ids = storage
+        .list()?
+        .into_iter()
+        // Get out the ID part at the front of the name.
+        // Both `xyz_taters.json` and `xyz_config.json` give the same ID, so unique-ify them
+        .filter_map(|name| {
+            let id_end_idx = name.find(|c: char| !c.is_numeric())?;
+            name[..id_end_idx].parse::<u64>().ok()
+        })
+        .unique()
+        .sorted()
+        .map(GuildId)
+        .collect();
+    Ok(ids)
+}
+
+/// Load one guild's taters and config
+fn load_guild(storage: &dyn Storage, guild: GuildId) -> Result<Handler, anyhow::Error> {
+    let taters = storage
+        .read(&taters_name(guild))?
+        .with_context(|| format!("{} is missing", taters_name(guild)))?;
+    let config = storage
+        .read(&config_name(guild))?
+        .with_context(|| format!("{} is missing", config_name(guild)))?;
+
+    let taters: HandlerButOnlyTaters =
+        serde_json::from_str(&taters).with_context(|| format!("reading {}", taters_name(guild)))?;
+    let config: Config =
+        serde_json::from_str(&config).with_context(|| format!("reading {}", config_name(guild)))?;
+
+    let mut tatered_messages = taters.tatered_messages;
+    for msg in tatered_messages.values_mut() {
+        if msg.pin_id.is_some() && msg.pin_channel.is_none() {
+            msg.pin_channel = Some(config.pin_channel);
+        }
+    }
+    Ok(Handler {
+        config,
+        taters_between: vote_rings::count_pairs(tatered_messages.values()),
+        tatered_messages,
+        taters_given: taters.taters_given,
+        taters_got: taters.taters_got,
+        anomalies: VecDeque::new(),
+    })
+}
+
+/// Everything about one guild.
+///
+/// The handler lock is only ever held while reading or changing state, never across
+/// a call to Discord, so a slow request doesn't stall the guild's other events.
+struct GuildState {
+    handler: Mutex<Handler>,
+    /// Held for the whole of a pin update, so two reactions at once can't
+    /// both decide to create a pin for the same message
+    pin_sync: Mutex<()>,
+}
+
+impl GuildState {
+    fn new(handler: Handler) -> Self {
+        Self {
+            handler: Mutex::new(handler),
+            pin_sync: Mutex::new(()),
+        }
+    }
+
+    async fn lock(&self) -> MutexGuard<'_, Handler> {
+        let start = Instant::now();
+        let guard = self.handler.lock().await;
+        METRICS.lock_wait(start.elapsed());
+        guard
+    }
+
+    /// Count a tater, asking Discord who sent the message if we don't know yet.
+    /// Returns whether the message's pin needs updating.
+    async fn do_add_tater<D: Discord + ?Sized>(
+        &self,
+        discord: &D,
+        reaction: &TaterReaction,
+        bot_uid: UserId,
+    ) -> Result<bool, anyhow::Error> {
+        // ok this is a tater! but only if they're allowed to give one
+        let (rejection, cached_sender) = {
+            let this = self.lock().await;
+            if this.config.old_messages == OldMessages::Ignore
+                && this.config.too_old(reaction.message_id)
+            {
+                log::debug!(
+                    "Not counting a tater on message {}, which is too old",
+                    reaction.message_id
+                );
+                return Ok(false);
+            }
+            if reaction.by_bot && !this.config.bot_policy.bot_reactions {
+                log::debug!(
+                    "Not counting a tater from {}, who's a bot",
+                    reaction.user_id
+                );
+                return Ok(false);
+            }
+            let rejection = if this.config.banned_from_giving(reaction.user_id) {
+                Some("they're banned from giving taters".to_owned())
+            } else {
+                this.config.giver_requirements.rejection(
+                    reaction.user_id,
+                    reaction.member.as_ref(),
+                    unix_now(),
+                )
+            };
+            let sender = this
+                .tatered_messages
+                .get(&reaction.message_id)
+                .map(|msg| msg.sender);
+            (rejection, sender)
+        };
+        if let Some(why) = rejection {
+            self.not_counted(discord, reaction, &why).await;
+            return Ok(false);
+        }
+
+        // Only ask Discord who sent it if it isn't in the cache yet
+        let sender = match cached_sender {
+            Some(it) => it,
+            None => {
+                let author = discord
+                    .get_author(reaction.channel_id, reaction.message_id)
+                    .await
+                    .context("Getting message for reaction")?;
+                match self.credit_for(discord, &author, bot_uid).await? {
+                    Some(it) => it,
+                    None => return Ok(false),
+                }
+            }
+        };
+
+        let why = {
+            let mut this = self.lock().await;
+            match this.refusal(reaction.user_id, sender) {
+                Some(why) => why,
+                None => return Ok(this.add_tater(reaction, Some(sender))),
+            }
+        };
+        self.not_counted(discord, reaction, &why).await;
+        Ok(false)
+    }
+
+    /// Who gets the taters on a message, or None if it can't get any,
+    /// like if a bot sent it and bots' messages don't count
+    async fn credit_for<D: Discord + ?Sized>(
+        &self,
+        discord: &D,
+        author: &MessageAuthor,
+        bot_uid: UserId,
+    ) -> Result<Option<UserId>, anyhow::Error> {
+        if author.author_id == bot_uid || author.system {
+            return Ok(None);
+        }
+        let (policy, ours) = {
+            let this = self.lock().await;
+            let ours = author
+                .webhook_id
+                .is_some_and(|id| this.config.webhook(id).is_some());
+            (this.config.bot_policy.clone(), ours)
+        };
+        match author.webhook_id {
+            // One of our own pins, in webhook mode
+            Some(_) if ours => Ok(None),
+            Some(_) => match policy.webhook_messages {
+                WebhookMessages::Ignore => Ok(None),
+                WebhookMessages::Count => Ok(Some(author.author_id)),
+                WebhookMessages::CreditProxies => {
+                    let proxied_for = discord
+                        .proxied_for(author.id)
+                        .await
+                        .context("Finding out who a proxied message is from")?;
+                    Ok(Some(proxied_for.unwrap_or(author.author_id)))
+                }
+            },
+            None if author.bot && !policy.bot_messages => Ok(None),
+            None => Ok(Some(author.author_id)),
+        }
+    }
+
+    /// Note a tater that didn't count, and why, in the mod log if there is one
+    async fn not_counted<D: Discord + ?Sized>(
+        &self,
+        discord: &D,
+        reaction: &TaterReaction,
+        why: &str,
+    ) {
+        log::debug!(
+            "Not counting a tater from {} on message {}: {}",
+            reaction.user_id,
+            reaction.message_id,
+            why
+        );
+        let channel = match self.lock().await.config.mod_log_channel {
+            Some(it) => it,
+            None => return,
+        };
+        let text = format!(
+            "Didn't count a tater from {} on {} because {}",
+            reaction.user_id.mention(),
+            reaction
+                .message_id
+                .link(reaction.channel_id, Some(reaction.guild_id)),
+            why
+        );
+        if let Err(oh_no) = discord.send_log(channel, &text).await {
+            log::warn!("Couldn't post to the mod log: {:?}", oh_no);
+        }
+    }
+}
+
+/// A reaction, boiled down to what the handler cares about
+#[derive(Debug, Clone)]
+pub struct TaterReaction {
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    /// Who reacted
+    pub user_id: UserId,
+    pub emoji: ReactionType,
+    /// Their membership in the guild, if Discord sent it along
+    pub member: Option<ReactingMember>,
+    /// Whether they're a bot, as far as we can tell
+    pub by_bot: bool,
+}
+
+impl PinKey {
+    fn of(reaction: &TaterReaction) -> Self {
+        Self {
+            guild: reaction.guild_id,
+            channel: reaction.channel_id,
+            message: reaction.message_id,
+        }
+    }
+}
+
+impl TaterReaction {
+    /// Returns None for reactions outside of guilds
+    /// Removals don't come with the member, so whether they're a bot comes from `cache`
+    /// if it's not in the reaction
+    pub async fn from_reaction(reaction: &Reaction, cache: &Cache) -> Option<Self> {
+        let user_id = reaction.user_id?;
+        let by_bot = match reaction
+            .member
+            .as_ref()
+            .and_then(|member| member.user.as_ref())
+        {
+            Some(user) => user.bot,
+            None => cache.user(user_id).await.is_some_and(|user| user.bot),
+        };
+        Some(Self {
+            guild_id: reaction.guild_id?,
+            channel_id: reaction.channel_id,
+            message_id: reaction.message_id,
+            user_id,
+            emoji: reaction.emoji.clone(),
+            member: reaction.member.as_ref().map(|member| ReactingMember {
+                joined_at: member.joined_at.map(|it| it.timestamp()),
+                roles: member.roles.clone(),
+            }),
+            by_bot,
+        })
     }
 }
 
@@ -186,6 +625,13 @@ struct Handler {
     taters_got: HashMap<UserId, u64>,
     /// How many taters each user has posted
     taters_given: HashMap<UserId, u64>,
+    /// Recent tater changes that didn't add up, oldest first. Not saved.
+    #[serde(skip)]
+    anomalies: VecDeque<String>,
+    /// How many taters each giver has given each receiver, keyed by `(giver, receiver)`.
+    /// Worked out from `givers` when loading, so not saved.
+    #[serde(skip)]
+    taters_between: HashMap<(UserId, UserId), u64>,
 }
 
 /// Wrapper struct that only stores info about the taters so `Handler`
@@ -208,202 +654,307 @@ struct HandlerButOnlyTatersRef<'a> {
 }
 
 impl Handler {
+    /// Only keep this many of the latest anomalies
+    const MAX_ANOMALIES: usize = 50;
+
     fn new() -> Self {
         Self {
             config: Config::new(),
             tatered_messages: HashMap::new(),
             taters_got: HashMap::new(),
             taters_given: HashMap::new(),
+            anomalies: VecDeque::new(),
+            taters_between: HashMap::new(),
         }
     }
 
-    async fn do_add_tater(
-        &mut self,
-        ctx: &Context,
-        reaction: &Reaction,
-        bot_uid: UserId,
-    ) -> Result<(), anyhow::Error> {
-        // ok this is a tater!
-        let giver = reaction
-            .user(&ctx.http)
-            .await
-            .context("Getting user for reaction")?;
+    /// Is this reaction a tater we should be counting?
+    fn counts_reaction(&self, reaction: &TaterReaction) -> bool {
+        self.config.tater_emoji == reaction.emoji
+            && !self
+                .config
+                .blacklisted_channels
+                .contains(&reaction.channel_id)
+    }
 
+    /// Why a tater from `giver` to `receiver` shouldn't count, if it shouldn't
+    fn refusal(&self, giver: UserId, receiver: UserId) -> Option<String> {
+        if giver != receiver && self.config.banned_from_receiving(receiver) {
+            return Some(format!(
+                "{} is banned from receiving taters",
+                receiver.mention()
+            ));
+        }
+        self.over_pair_cap(giver, receiver)
+    }
+
+    /// Count a tater on a message.
+    /// `sender` is only needed if the message isn't in the cache yet.
+    /// Returns whether anything changed.
+    fn add_tater(&mut self, reaction: &TaterReaction, sender: Option<UserId>) -> bool {
         // Update taters received and taters on this message via the cache
-        let tatered_message = {
-            let tatered_message = self.tatered_messages.entry(reaction.message_id);
-            let tatered_message = match tatered_message {
-                hash_map::Entry::Occupied(o) => o.into_mut(),
-                hash_map::Entry::Vacant(v) => {
-                    // this is empty, so we need to fill the cache
-                    let message = reaction
-                        .message(&ctx.http)
-                        .await
-                        .with_context(|| "Getting message for reaction")?;
-                    if message.author.id == bot_uid {
-                        return Ok(());
-                    }
-                    v.insert(TateredMessage::new(message.author.id, 0, None))
-                }
-            };
-            if Some(tatered_message.sender) == reaction.user_id {
-                // hey you can't do your own message!
-                return Ok(());
-            }
-            // one more potato on this message
-            tatered_message.count += 1;
-            // smuggle out the message to avoid borrow errors
-            tatered_message.clone()
+        let tatered_message = match self.tatered_messages.entry(reaction.message_id) {
+            hash_map::Entry::Occupied(o) => o.into_mut(),
+            hash_map::Entry::Vacant(v) => match sender {
+                Some(sender) => v.insert(TateredMessage::new(sender, 0, None)),
+                None => return false,
+            },
         };
+        tatered_message.channel.get_or_insert(reaction.channel_id);
+        if !tatered_message.counts_from(reaction.user_id) {
+            return false;
+        }
+        if tatered_message.givers.contains(&reaction.user_id) {
+            // You can only react once, so we must have missed them taking it back
+            self.anomaly(reaction, "got a second tater from the same person");
+            return false;
+        }
+        // one more potato on this message
+        tatered_message.count = tatered_message.count.saturating_add(1);
+        tatered_message.givers.push(reaction.user_id);
+        let sender = tatered_message.sender;
+        let between = self
+            .taters_between
+            .entry((reaction.user_id, sender))
+            .or_insert(0);
+        *between = between.saturating_add(1);
 
         // the giver gave one more potato
-        *self.taters_given.entry(giver.id).or_insert(0) += 1;
+        let given = self.taters_given.entry(reaction.user_id).or_insert(0);
+        *given = given.saturating_add(1);
         // this person got one more potato
-        *self.taters_got.entry(tatered_message.sender).or_insert(0) += 1;
-
-        let new_pin_id = update_pin_message(self, &tatered_message, &reaction, &ctx)
-            .await
-            .context("Update pin message")?;
-        if let Some(tm) = self.tatered_messages.get_mut(&reaction.message_id) {
-            tm.pin_id = new_pin_id
-        }
-        Ok(())
+        let got = self.taters_got.entry(sender).or_insert(0);
+        *got = got.saturating_add(1);
+        true
     }
 
-    async fn do_remove_tater(
-        &mut self,
-        ctx: &Context,
-        reaction: &Reaction,
-    ) -> Result<(), anyhow::Error> {
-        if self.config.tater_emoji != reaction.emoji {
-            return Ok(());
-        }
-        if self
-            .config
-            .blacklisted_channels
-            .contains(&reaction.channel_id)
-        {
-            return Ok(());
-        }
-        // ok this is a tater!
-        let ungiver = reaction.user(&ctx.http).await?;
-        log::trace!(
-            "tater removed by {:?} from message {:?}",
-            reaction.user_id,
-            reaction.message_id
-        );
-
+    /// Take a tater off a message. Returns whether anything changed.
+    ///
+    /// Counts never go below zero. Taking back a tater that was never counted,
+    /// like one given while we were offline, is noted as an anomaly and ignored.
+    fn remove_tater(&mut self, reaction: &TaterReaction) -> bool {
         // Update taters received and taters on this message via the cache
-        let tatered_message = {
-            let tatered_message = self.tatered_messages.entry(reaction.message_id);
-            let tatered_message = match tatered_message {
-                hash_map::Entry::Occupied(o) => o.into_mut(),
-                hash_map::Entry::Vacant(..) => {
-                    // this should never be an empty entry
-                    log::error!("`reaction_remove`: there was an empty entry in `tatered_messages`. This probably means someone un-reacted to a message this bot did not know about, from before the bot was introduced.");
-                    return Ok(());
-                }
-            };
-            if Some(tatered_message.sender) == reaction.user_id {
-                // hey you can't do your own message!
-                return Ok(());
+        let tatered_message = match self.tatered_messages.get_mut(&reaction.message_id) {
+            Some(it) => it,
+            None => {
+                // Either it's from before the bot was introduced, or it's one of our own
+                // messages, which we never count. Nothing to take back either way.
+                log::debug!(
+                    "`reaction_remove` on message {}, which has no taters",
+                    reaction.message_id
+                );
+                return false;
             }
-            // one fewer potato on this message
-            tatered_message.count -= 1;
-            // smuggle out the message to avoid borrow errors
-            tatered_message.clone()
         };
+        if !tatered_message.counts_from(reaction.user_id) {
+            return false;
+        }
+        if tatered_message.count == 0 {
+            self.anomaly(reaction, "had a tater taken back while it had none");
+            return false;
+        }
+        match tatered_message
+            .givers
+            .iter()
+            .position(|giver| *giver == reaction.user_id)
+        {
+            Some(idx) => {
+                tatered_message.givers.remove(idx);
+                let pair = (reaction.user_id, tatered_message.sender);
+                take_one(&mut self.taters_between, pair);
+            }
+            // Older saves don't know who gave what, so those taters can still be taken back
+            None if tatered_message.count > tatered_message.givers.len() as u64 => {}
+            // Probably one we didn't count on purpose, like from a banned user
+            None if self.config.refuses_taters()
+                || reaction.by_bot
+                || self.config.too_old(reaction.message_id) =>
+            {
+                log::debug!(
+                    "Not taking back a tater from {} on message {}, which wasn't counted",
+                    reaction.user_id,
+                    reaction.message_id
+                );
+                return false;
+            }
+            None => {
+                self.anomaly(reaction, "had a tater taken back that was never counted");
+                return false;
+            }
+        }
+        // one fewer potato on this message
+        tatered_message.count -= 1;
+        let sender = tatered_message.sender;
 
         // the ungiver reduces potato
-        *self.taters_given.entry(ungiver.id).or_insert(0) -= 1;
+        if !take_one(&mut self.taters_given, reaction.user_id) {
+            self.anomaly(
+                reaction,
+                "had a tater taken back by someone who'd given none",
+            );
+        }
         // this person lost a potato
-        *self.taters_got.entry(tatered_message.sender).or_insert(0) -= 1;
+        if !take_one(&mut self.taters_got, sender) {
+            self.anomaly(
+                reaction,
+                "had a tater taken back from someone who'd received none",
+            );
+        }
+        true
+    }
 
-        let new_pin_id = update_pin_message(self, &tatered_message, &reaction, &ctx)
-            .await
-            .context("update_pin_message")?;
-        if let Some(tm) = self.tatered_messages.get_mut(&reaction.message_id) {
-            tm.pin_id = new_pin_id
+    /// Note that a tater change didn't add up, so it shows up in `diagnostics`
+    fn anomaly(&mut self, reaction: &TaterReaction, what: &str) {
+        log::warn!(
+            "Message {} in guild {} {} (from {})",
+            reaction.message_id,
+            reaction.guild_id,
+            what,
+            reaction.user_id
+        );
+        METRICS.tater_anomaly();
+        let now = unix_now();
+        if self.anomalies.len() >= Handler::MAX_ANOMALIES {
+            self.anomalies.pop_front();
         }
-        Ok(())
+        self.anomalies.push_back(format!(
+            "<t:{}:R>: message {} {} (from {})",
+            now,
+            reaction
+                .message_id
+                .link(reaction.channel_id, Some(reaction.guild_id)),
+            what,
+            reaction.user_id.mention()
+        ));
+    }
+}
+
+/// The current time as a unix timestamp
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Take one off a user's total. Returns false, leaving it at zero, if they didn't have any.
+fn take_one<K: Eq + Hash>(totals: &mut HashMap<K, u64>, key: K) -> bool {
+    let total = totals.entry(key).or_insert(0);
+    match total.checked_sub(1) {
+        Some(it) => {
+            *total = it;
+            true
+        }
+        None => false,
     }
 }
 
 #[async_trait]
 impl EventHandler for HandlerWrapper {
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        METRICS.event(Event::Ready);
         log::info!(
             "{}#{} is connected!",
             ready.user.name,
             ready.user.discriminator
         );
-        let mut g = self.bot_user_id.write().await;
-        *g = Some(ready.user.id);
+        self.set_bot_uid(ready.user.id).await;
+        self.connected.store(true, Ordering::SeqCst);
+        self.start_pin_worker(Arc::new(SerenityDiscord::new(&ctx)));
+        self.start_updates(ctx).await;
+    }
+
+    async fn shard_stage_update(&self, _: Context, update: ShardStageUpdateEvent) {
+        self.on_shard_stage_update(&update);
+    }
+
+    async fn guild_create(&self, ctx: Context, guild: Guild, _is_new: bool) {
+        METRICS.event(Event::GuildCreate);
+        if let Err(oh_no) = self.sync_guild_commands(&ctx.http, guild.id).await {
+            log::error!("`guild_create`: {:?}", oh_no);
+        }
     }
 
     async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
-        let guild_id = match reaction.guild_id {
+        METRICS.event(Event::ReactionAdd);
+        if self.is_shutting_down() {
+            return;
+        }
+        let reaction = match TaterReaction::from_reaction(&reaction, &ctx.cache).await {
             Some(it) => it,
             None => return,
         };
-        let mut handlers = self.handlers.lock().await;
-        let this = handlers.entry(guild_id).or_insert_with(Handler::new);
+        self.on_reaction_add(&SerenityDiscord::new(&ctx), &reaction)
+            .await;
+    }
 
-        if this.config.tater_emoji != reaction.emoji {
+    async fn reaction_remove(&self, ctx: Context, reaction: Reaction) {
+        METRICS.event(Event::ReactionRemove);
+        if self.is_shutting_down() {
             return;
         }
+        let reaction = match TaterReaction::from_reaction(&reaction, &ctx.cache).await {
+            Some(it) => it,
+            None => return,
+        };
+        self.on_reaction_remove(&reaction).await;
+    }
 
-        if this
-            .config
-            .blacklisted_channels
-            .contains(&reaction.channel_id)
-        {
+    async fn message(&self, ctx: Context, message: Message) {
+        METRICS.event(Event::Message);
+        if message.author.bot || self.is_shutting_down() {
             return;
         }
 
-        log::trace!(
-            "tater added by {:?} to message {:?}",
-            reaction.user_id,
-            reaction.message_id
-        );
-        let bot_uid = self.bot_uid().await;
-        if let Err(oh_no) = this.do_add_tater(&ctx, &reaction, bot_uid).await {
-            log::error!("`reaction_add`: {:?}", oh_no);
-        }
-    }
-
-    async fn reaction_remove(&self, ctx: Context, reaction: Reaction) {
-        let guild_id = match reaction.guild_id {
+        let uid = match self.bot_uid().await {
             Some(it) => it,
             None => return,
         };
-        let mut handlers = self.handlers.lock().await;
-        let this = handlers.entry(guild_id).or_insert_with(Handler::new);
-
-        if let Err(oh_no) = this.do_remove_tater(&ctx, &reaction).await {
-            log::error!("`reaction_remove`: {:?}", oh_no);
+        let res = commands::handle_commands(self, &ctx, uid, &message).await;
+        if let Err(oh_no) = res {
+            log::error!("`message`: {:?}", oh_no);
         }
     }
 
-    async fn message(&self, ctx: Context, message: Message) {
-        if message.author.bot {
+    async fn message_delete(
+        &self,
+        _: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        guild_id: Option<GuildId>,
+    ) {
+        METRICS.event(Event::MessageDelete);
+        if self.is_shutting_down() {
             return;
         }
-
-        // Try every time it sees a message.
-        // I figure that's often enough
-        if let Err(oh_no) = self.check_updates(&ctx).await {
-            log::error!("`message`: {:?}", oh_no);
+        if let Some(guild_id) = guild_id {
+            self.on_messages_deleted(guild_id, channel_id, &[deleted_message_id])
+                .await;
         }
+    }
 
-        let uid = self.bot_uid().await;
-        let res = commands::handle_commands(self, &ctx, uid, &message).await;
-        if let Err(oh_no) = res {
-            log::error!("`message`: {:?}", oh_no);
+    async fn message_delete_bulk(
+        &self,
+        _: Context,
+        channel_id: ChannelId,
+        multiple_deleted_messages_ids: Vec<MessageId>,
+        guild_id: Option<GuildId>,
+    ) {
+        METRICS.event(Event::MessageDelete);
+        if self.is_shutting_down() {
+            return;
+        }
+        if let Some(guild_id) = guild_id {
+            self.on_messages_deleted(guild_id, channel_id, &multiple_deleted_messages_ids)
+                .await;
         }
     }
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        METRICS.event(Event::Interaction);
+        if self.is_shutting_down() {
+            return;
+        }
         if interaction.kind == InteractionType::ApplicationCommand {
             if let Err(e) = commands::handle_slash_command(self, ctx, interaction).await {
                 log::error!("Handling slash command: {}", e);
@@ -412,131 +963,8 @@ impl EventHandler for HandlerWrapper {
     }
 }
 
-/// Return what we need to update the pin message ID to
-async fn update_pin_message(
-    this: &mut Handler,
-    tatered_message: &TateredMessage,
-    reaction: &Reaction,
-    ctx: &Context,
-) -> Result<Option<MessageId>, anyhow::Error> {
-    let medal_idx = (tatered_message.count as f32 / this.config.threshold as f32)
-        .log2()
-        .floor();
-    let medal: &str = if medal_idx >= 0.0 {
-        // we made it, nice
-        match this.config.medals.get(medal_idx as usize) {
-            Some(it) => it.as_str(),
-            None => this.config.medals.last().map(|s| s.as_str()).unwrap_or("?"),
-        }
-    } else {
-        // oh no we gotta delete that now ;-;
-        if let Some(mid) = tatered_message.pin_id {
-            // delete the message
-            let msg = ctx
-                .http
-                .get_message(this.config.pin_channel.0, mid.0)
-                .await?;
-            msg.delete(&ctx.http).await.context("deleting pin")?;
-        }
-        return Ok(None);
-    };
-
-    let content = format!("{} {}", medal, tatered_message.count);
-
-    match tatered_message.pin_id {
-        Some(mid) => {
-            log::trace!("Editing existing pin message {}", mid);
-            // we just need to edit the header
-            let mut msg = ctx
-                .http
-                .get_message(this.config.pin_channel.0, mid.0)
-                .await
-                .with_context(|| {
-                    format!(
-                        "getting message {} from channel {}",
-                        mid.0, this.config.pin_channel.0
-                    )
-                })?;
-            msg.edit(&ctx.http, |m| m.content(content))
-                .await
-                .context("updating pin text")?;
-            // Don't change anything
-            Ok(tatered_message.pin_id)
-        }
-        None => {
-            log::trace!("Creating new pin message");
-            // Must both create and edit message
-            let original_message = reaction.message(&ctx.http).await?;
-            let content_safe = original_message.content_safe(&ctx.cache).await;
-
-            let author_name = original_message
-                .author_nick(&ctx.http)
-                .await
-                .unwrap_or_else(|| original_message.author.name.clone());
-            let author_url = original_message.author.face();
-
-            let message_link = match reaction.guild_id {
-                Some(guild_id) => format!(
-                    "https://discord.com/channels/{}/{}/{}",
-                    guild_id.0, original_message.channel_id.0, original_message.id.0
-                ),
-                None => format!(
-                    "https://discord.com/channels/@me/{}/{}",
-                    original_message.channel_id.0, original_message.id.0
-                ),
-            };
-
-            let previous_message_count = this
-                .tatered_messages
-                .iter()
-                .filter_map(|(_id, msg)| {
-                    if msg.sender == tatered_message.sender {
-                        Some(())
-                    } else {
-                        None
-                    }
-                })
-                .count()
-                - 1;
-
-            let image = original_message
-                .attachments
-                .get(0)
-                .and_then(|att| att.dimensions().map(|_dims| &att.url));
-
-            let msg = this
-                .config
-                .pin_channel
-                .send_message(&ctx.http, |m| {
-                    m.content(content).embed(|e| {
-                        e.author(|a| a.name(author_name).icon_url(author_url))
-                            .description(content_safe)
-                            // zero width space
-                            .field(
-                                "\u{200b}",
-                                format!("[**Click to jump to message!**]({})", message_link),
-                                false,
-                            )
-                            .footer(|f| {
-                                f.text(format!(
-                                    "This user has been pinned {} times before",
-                                    previous_message_count,
-                                ))
-                            });
-                        if let Some(image) = image {
-                            e.image(image);
-                        }
-                        e
-                    })
-                })
-                .await?;
-            Ok(Some(msg.id))
-        }
-    }
-}
-
 /// Configuration for the handler
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
     /// The trigger word for bot administration commands
     pub trigger_word: String,
@@ -557,6 +985,68 @@ pub struct Config {
 
     /// people who can administrate the bot
     pub admins: HashSet<UserId>,
+
+    /// Optional commands this guild has turned off
+    #[serde(default)]
+    pub disabled_command_groups: HashSet<CommandGroup>,
+    /// How pins are laid out
+    #[serde(default)]
+    pub pin_template: PinTemplate,
+    /// Whether pins are embeds or go through a webhook
+    #[serde(default)]
+    pub pin_mode: PinMode,
+    /// The webhook pins go through, once one's been made
+    #[serde(default)]
+    pub pin_webhook: Option<PinWebhook>,
+    /// Webhooks from old pin channels, so the pins they sent can still be updated and deleted
+    #[serde(default)]
+    pub old_pin_webhooks: Vec<PinWebhook>,
+    /// Whether pins of replies show what they replied to
+    #[serde(default = "yes")]
+    pub show_replies: bool,
+    /// What to do when someone else deletes a pin
+    #[serde(default)]
+    pub deleted_pins: DeletedPins,
+    /// Who can give taters that count
+    #[serde(default)]
+    pub giver_requirements: GiverRequirements,
+    /// Where to note taters that didn't count, if anywhere
+    #[serde(default)]
+    pub mod_log_channel: Option<ChannelId>,
+    /// How many of one person's taters can count for another, if there's a limit
+    #[serde(default)]
+    pub pair_cap: Option<u64>,
+    /// Users whose taters don't count, one way or both
+    #[serde(default)]
+    pub banned_users: HashMap<UserId, UserBan>,
+    /// Which bots and webhooks taters count for and from
+    #[serde(default)]
+    pub bot_policy: BotPolicy,
+    /// How old a message can be, in hours, before new taters on it are treated
+    /// as `old_messages` says
+    #[serde(default)]
+    pub max_message_age_hours: Option<u64>,
+    /// Whether new taters on messages that old are ignored, or counted without pinning
+    #[serde(default)]
+    pub old_messages: OldMessages,
+    /// What to do with pins of messages from NSFW channels
+    #[serde(default)]
+    pub nsfw_pins: NsfwPins,
+}
+
+/// What to do with new taters on messages older than `max_message_age_hours`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OldMessages {
+    /// Don't count them
+    #[default]
+    Ignore,
+    /// Count them, but don't pin the message if it isn't already
+    NoPin,
+}
+
+fn yes() -> bool {
+    true
 }
 
 impl Config {
@@ -586,8 +1076,61 @@ impl Config {
                 set.insert(UserId(273636822565912578));
                 set
             },
+            disabled_command_groups: HashSet::new(),
+            pin_template: PinTemplate::default(),
+            pin_mode: PinMode::default(),
+            pin_webhook: None,
+            old_pin_webhooks: Vec::new(),
+            show_replies: true,
+            deleted_pins: DeletedPins::default(),
+            giver_requirements: GiverRequirements::default(),
+            mod_log_channel: None,
+            pair_cap: None,
+            banned_users: HashMap::new(),
+            bot_policy: BotPolicy::default(),
+            max_message_age_hours: None,
+            old_messages: OldMessages::default(),
+            nsfw_pins: NsfwPins::default(),
         }
     }
+
+    /// Whether a message is older than `max_message_age_hours`, going by its snowflake
+    fn too_old(&self, message: MessageId) -> bool {
+        self.too_old_at(message, unix_now())
+    }
+
+    /// `too_old` as of `now`, a unix timestamp
+    fn too_old_at(&self, message: MessageId, now: i64) -> bool {
+        let max_age = match self.max_message_age_hours {
+            Some(it) => it,
+            None => return false,
+        };
+        let age = now - message.created_at().timestamp();
+        age > 0 && age as u64 / 60 / 60 >= max_age
+    }
+
+    /// One of our webhooks, current or old, to reach the pins it sent
+    fn webhook(&self, id: WebhookId) -> Option<&PinWebhook> {
+        self.pin_webhook
+            .iter()
+            .chain(&self.old_pin_webhooks)
+            .find(|webhook| webhook.id == id)
+    }
+
+    fn banned_from_giving(&self, user: UserId) -> bool {
+        self.banned_users.get(&user).is_some_and(|ban| ban.giving())
+    }
+
+    fn banned_from_receiving(&self, user: UserId) -> bool {
+        self.banned_users
+            .get(&user)
+            .is_some_and(|ban| ban.receiving())
+    }
+
+    /// Whether some taters might not have been counted on purpose
+    fn refuses_taters(&self) -> bool {
+        self.giver_requirements.any() || self.pair_cap.is_some() || !self.banned_users.is_empty()
+    }
 }
 
 /// Handle to a message with potatoes on it
@@ -595,18 +1138,213 @@ impl Config {
 struct TateredMessage {
     /// ID of the sender
     sender: UserId,
+    /// Channel it was sent in. Older saves don't have it.
+    #[serde(default)]
+    channel: Option<ChannelId>,
     /// Number of taters on it
     count: u64,
     /// If this is pinned, has the ID of the pin message
     pin_id: Option<MessageId>,
+    /// Channel the pin is in. Older saves don't have it, so those are
+    /// taken to be in the pin channel when they're loaded.
+    #[serde(default)]
+    pin_channel: Option<ChannelId>,
+    /// Who gave it taters, in the order they did
+    #[serde(default)]
+    givers: Vec<UserId>,
+    /// If the pin was sent through a webhook, which one
+    #[serde(default)]
+    pin_webhook: Option<WebhookId>,
+    /// A moderator's say on whether it's pinned, which beats its count
+    #[serde(default)]
+    pin_override: Option<PinOverride>,
+    /// A moderator froze its count, so taters on it aren't counted
+    #[serde(default)]
+    locked: bool,
+}
+
+/// Whether a moderator wants a message pinned, whatever its count
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PinOverride {
+    /// Pinned even without enough taters
+    Force,
+    /// Never pinned, however many taters it gets
+    Block,
 }
 
 impl TateredMessage {
+    /// Whether a tater from `user` counts towards this message
+    fn counts_from(&self, user: UserId) -> bool {
+        // hey you can't do your own message! and locked counts stay put
+        self.sender != user && !self.locked
+    }
+
     fn new(sender: UserId, tater_count: u64, pin: Option<MessageId>) -> Self {
         Self {
             sender,
+            channel: None,
             count: tater_count,
             pin_id: pin,
+            pin_channel: None,
+            givers: Vec::new(),
+            pin_webhook: None,
+            pin_override: None,
+            locked: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tater from `giver` on `message`, in guild 1 and channel 10
+    pub(super) fn tater(handler: &Handler, giver: u64, message: u64) -> TaterReaction {
+        TaterReaction {
+            guild_id: GuildId(1),
+            channel_id: ChannelId(10),
+            message_id: MessageId(message),
+            user_id: UserId(giver),
+            emoji: handler.config.tater_emoji.clone(),
+            member: None,
+            by_bot: false,
+        }
+    }
+
+    /// A snowflake ID for something made at the unix timestamp `secs`
+    pub(super) fn snowflake_at(secs: i64) -> u64 {
+        ((secs as u64 * 1000 - 1_420_070_400_000) << 22) + 1
+    }
+
+    /// Give a tater on a message from `sender`, like `do_add_tater` would once it knows who sent it
+    pub(super) fn give(handler: &mut Handler, giver: u64, sender: u64, message: u64) -> bool {
+        let reaction = tater(handler, giver, message);
+        handler.add_tater(&reaction, Some(UserId(sender)))
+    }
+
+    fn take(handler: &mut Handler, giver: u64, message: u64) -> bool {
+        let reaction = tater(handler, giver, message);
+        handler.remove_tater(&reaction)
+    }
+
+    fn totals(handler: &Handler, user: u64) -> (u64, u64) {
+        let get = |totals: &HashMap<UserId, u64>| totals.get(&UserId(user)).copied().unwrap_or(0);
+        (get(&handler.taters_got), get(&handler.taters_given))
+    }
+
+    #[test]
+    fn giving_and_taking_back_add_up() {
+        let mut handler = Handler::new();
+        assert!(give(&mut handler, 2, 5, 100));
+        assert!(give(&mut handler, 3, 5, 100));
+        assert_eq!(handler.tatered_messages[&MessageId(100)].count, 2);
+        assert_eq!(totals(&handler, 5), (2, 0));
+        assert_eq!(totals(&handler, 2), (0, 1));
+
+        assert!(take(&mut handler, 2, 100));
+        assert_eq!(handler.tatered_messages[&MessageId(100)].count, 1);
+        assert_eq!(
+            handler.tatered_messages[&MessageId(100)].givers,
+            vec![UserId(3)]
+        );
+        assert_eq!(totals(&handler, 5), (1, 0));
+        assert_eq!(totals(&handler, 2), (0, 0));
+        assert!(handler.anomalies.is_empty());
+        assert!(handler.problems().is_empty(), "{:?}", handler.problems());
+    }
+
+    #[test]
+    fn own_and_locked_messages_dont_count() {
+        let mut handler = Handler::new();
+        assert!(!give(&mut handler, 5, 5, 100));
+        assert_eq!(totals(&handler, 5), (0, 0));
+
+        assert!(give(&mut handler, 2, 5, 100));
+        handler
+            .tatered_messages
+            .get_mut(&MessageId(100))
+            .unwrap()
+            .locked = true;
+        assert!(!give(&mut handler, 3, 5, 100));
+        assert!(!take(&mut handler, 2, 100));
+        assert_eq!(handler.tatered_messages[&MessageId(100)].count, 1);
+    }
+
+    #[test]
+    fn counts_never_go_below_zero() {
+        let mut handler = Handler::new();
+        // Nothing known about it, so nothing to take back
+        assert!(!take(&mut handler, 2, 100));
+
+        assert!(give(&mut handler, 2, 5, 100));
+        // Given while we were offline, so never counted
+        assert!(!take(&mut handler, 3, 100));
+        assert_eq!(handler.anomalies.len(), 1);
+        assert!(take(&mut handler, 2, 100));
+        assert!(!take(&mut handler, 2, 100));
+        assert_eq!(handler.anomalies.len(), 2);
+
+        assert_eq!(handler.tatered_messages[&MessageId(100)].count, 0);
+        assert_eq!(totals(&handler, 5), (0, 0));
+        assert_eq!(totals(&handler, 2), (0, 0));
+        assert_eq!(totals(&handler, 3), (0, 0));
+    }
+
+    #[test]
+    fn second_taters_from_one_person_are_anomalies() {
+        let mut handler = Handler::new();
+        assert!(give(&mut handler, 2, 5, 100));
+        assert!(!give(&mut handler, 2, 5, 100));
+        assert_eq!(handler.tatered_messages[&MessageId(100)].count, 1);
+        assert_eq!(handler.anomalies.len(), 1);
+    }
+
+    #[test]
+    fn old_saves_can_still_have_taters_taken_back() {
+        let mut handler = Handler::new();
+        handler
+            .tatered_messages
+            .insert(MessageId(100), TateredMessage::new(UserId(5), 2, None));
+        handler.taters_got.insert(UserId(5), 2);
+        handler.taters_given.insert(UserId(2), 1);
+
+        assert!(take(&mut handler, 2, 100));
+        assert_eq!(handler.tatered_messages[&MessageId(100)].count, 1);
+        // Nobody's known to have given the other one, so that doesn't add up
+        assert!(take(&mut handler, 3, 100));
+        assert_eq!(totals(&handler, 5), (0, 0));
+        assert_eq!(handler.anomalies.len(), 1);
+    }
+
+    #[test]
+    fn messages_get_too_old_on_the_hour() {
+        let mut config = Config::new();
+        let sent = 1_700_000_000;
+        assert!(!config.too_old_at(
+            MessageId(snowflake_at(sent)),
+            sent + 10 * 365 * 24 * 60 * 60
+        ));
+
+        config.max_message_age_hours = Some(24);
+        let day = 24 * 60 * 60;
+        assert!(!config.too_old_at(MessageId(snowflake_at(sent)), sent));
+        assert!(!config.too_old_at(MessageId(snowflake_at(sent)), sent + day - 1));
+        assert!(config.too_old_at(MessageId(snowflake_at(sent)), sent + day));
+        // Clocks disagree sometimes, so messages from the future aren't too old
+        assert!(!config.too_old_at(MessageId(snowflake_at(sent)), sent - day));
+    }
+
+    #[test]
+    fn anomalies_only_keep_the_latest() {
+        let mut handler = Handler::new();
+        for message in 0..Handler::MAX_ANOMALIES as u64 + 5 {
+            take(&mut handler, 2, message);
+            handler
+                .tatered_messages
+                .insert(MessageId(message), TateredMessage::new(UserId(5), 0, None));
+            take(&mut handler, 2, message);
         }
+        assert_eq!(handler.anomalies.len(), Handler::MAX_ANOMALIES);
     }
 }
@@ -0,0 +1,527 @@
+//! Queues up pin updates so a busy message doesn't hammer Discord.
+//!
+//! Reactions just mark a message's pin as out of date. A single worker then brings each
+//! pin up to date a little later, so a burst of reactions turns into one edit with the
+//! latest count. Failed updates are retried with backoff, and the worker saves the queue
+//! whenever it changes so nothing's forgotten over a restart.
+//!
+//! Pins can also be queued to be redone from scratch, like after the template changes.
+//! Those are spread out so redoing a whole board doesn't crowd out everything else.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId, MessageId};
+use tokio::{
+    sync::{Mutex as AsyncMutex, Notify, RwLock},
+    time::Instant,
+};
+
+use super::{pins, GuildState};
+use crate::{discord::Discord, storage::Storage};
+
+/// A message whose pin needs bringing up to date
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(super) struct PinKey {
+    pub guild: GuildId,
+    /// Channel the tatered message is in
+    pub channel: ChannelId,
+    pub message: MessageId,
+}
+
+/// How to redo a pin from scratch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Rerender {
+    /// If it's in an old pin channel, post it again in the current one
+    #[serde(default)]
+    pub migrate: bool,
+    /// Delete the old pin after posting it again
+    #[serde(default)]
+    pub delete_old: bool,
+}
+
+/// A queued pin, as it's saved
+#[derive(Serialize, Deserialize)]
+struct SavedPin {
+    #[serde(flatten)]
+    key: PinKey,
+    #[serde(default)]
+    rerender: bool,
+    #[serde(flatten)]
+    how: Rerender,
+}
+
+struct Pending {
+    due: Instant,
+    /// Its count changed
+    update: bool,
+    /// Redo the whole pin, not just the count
+    rerender: Option<Rerender>,
+    /// How many times updating it has failed in a row
+    attempts: u32,
+    /// The worker is updating it right now
+    in_flight: bool,
+    /// It changed again while it was in flight, so it needs another go afterwards
+    dirty: bool,
+}
+
+pub(super) struct PinQueue {
+    pending: Mutex<HashMap<PinKey, Pending>>,
+    /// Poked whenever something new is queued
+    wake: Notify,
+    /// It's changed since it was last saved
+    unsaved: AtomicBool,
+    /// Held while saving, so an older copy never lands on top of a newer one
+    saving: AsyncMutex<()>,
+    storage: Arc<dyn Storage>,
+}
+
+impl PinQueue {
+    /// How long to wait after the first change, so later ones in a burst get folded in
+    const DEBOUNCE: Duration = Duration::from_secs(3);
+    /// Wait before the first retry; doubles each time after that
+    const RETRY_BASE: Duration = Duration::from_secs(5);
+    /// Never wait longer than this between retries
+    const RETRY_MAX: Duration = Duration::from_secs(60 * 5);
+    /// Log failures as errors once a pin's failed this many times in a row
+    const LOUD_ATTEMPTS: u32 = 10;
+    /// What the queue is saved as
+    const SAVE_NAME: &'static str = "pin_queue.json";
+    /// Time between pins being redone from scratch
+    const RERENDER_GAP: Duration = Duration::from_secs(1);
+
+    /// Load whatever was queued when we last stopped. All of it is due right away.
+    pub fn load(storage: Arc<dyn Storage>) -> Self {
+        let saved = storage
+            .read(PinQueue::SAVE_NAME)
+            .and_then(|json| match json {
+                Some(json) => Ok(serde_json::from_str(&json)?),
+                None => Ok(Vec::new()),
+            });
+        let saved: Vec<SavedPin> = saved.unwrap_or_else(|e| {
+            log::error!("Couldn't read the pin queue, starting afresh: {:?}", e);
+            Vec::new()
+        });
+        if !saved.is_empty() {
+            log::info!("Loaded {} queued pin updates", saved.len());
+        }
+
+        // Updates are due right away, and rerenders pick up where they left off
+        let now = Instant::now();
+        let mut next_rerender = now;
+        let pending = saved
+            .into_iter()
+            .map(|saved| {
+                let due = if saved.rerender {
+                    next_rerender += PinQueue::RERENDER_GAP;
+                    next_rerender
+                } else {
+                    now
+                };
+                (
+                    saved.key,
+                    Pending {
+                        due,
+                        update: !saved.rerender,
+                        rerender: Some(saved.how).filter(|_| saved.rerender),
+                        attempts: 0,
+                        in_flight: false,
+                        dirty: false,
+                    },
+                )
+            })
+            .collect();
+        Self {
+            pending: Mutex::new(pending),
+            wake: Notify::new(),
+            unsaved: AtomicBool::new(false),
+            saving: AsyncMutex::new(()),
+            storage,
+        }
+    }
+
+    /// Mark a message's pin as out of date
+    pub fn push(&self, key: PinKey) {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get_mut(&key) {
+            // It'll already pick up the latest count when it's done
+            Some(it) if !it.in_flight => it.update = true,
+            Some(it) => it.dirty = true,
+            None => {
+                pending.insert(
+                    key,
+                    Pending {
+                        due: Instant::now() + PinQueue::DEBOUNCE,
+                        update: true,
+                        rerender: None,
+                        attempts: 0,
+                        in_flight: false,
+                        dirty: false,
+                    },
+                );
+                self.unsaved.store(true, Ordering::SeqCst);
+                self.wake.notify_one();
+            }
+        }
+    }
+
+    /// Queue pins to be redone from scratch, one every so often.
+    /// Returns how many weren't already waiting to be.
+    pub fn push_rerenders(&self, keys: impl IntoIterator<Item = PinKey>, how: Rerender) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        // Go after any rerenders already queued
+        let now = Instant::now();
+        let mut due = pending
+            .values()
+            .filter(|it| it.rerender.is_some())
+            .map(|it| it.due)
+            .fold(now, Instant::max);
+        let mut queued = 0;
+        for key in keys {
+            let it = pending.entry(key).or_insert_with(|| Pending {
+                due: now,
+                update: false,
+                rerender: None,
+                attempts: 0,
+                in_flight: false,
+                dirty: false,
+            });
+            if it.rerender == Some(how) {
+                continue;
+            }
+            due += PinQueue::RERENDER_GAP;
+            it.rerender = Some(how);
+            it.due = it.due.max(due);
+            if it.in_flight {
+                it.dirty = true;
+            }
+            queued += 1;
+        }
+        self.unsaved.store(true, Ordering::SeqCst);
+        self.wake.notify_one();
+        queued
+    }
+
+    /// How many of a guild's pins are still waiting to be redone
+    pub fn rerenders_left(&self, guild: GuildId) -> usize {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .iter()
+            .filter(|(key, it)| key.guild == guild && it.rerender.is_some())
+            .count()
+    }
+
+    /// Stop redoing a guild's pins. Returns how many were still waiting.
+    /// They still get their counts updated if that was queued too.
+    pub fn cancel_rerenders(&self, guild: GuildId) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        let mut cancelled = 0;
+        pending.retain(|key, it| {
+            if key.guild != guild || it.rerender.is_none() {
+                return true;
+            }
+            it.rerender = None;
+            cancelled += 1;
+            // Don't bother if that's all it was waiting for
+            it.update || it.in_flight
+        });
+        self.unsaved.store(true, Ordering::SeqCst);
+        self.wake.notify_one();
+        cancelled
+    }
+
+    /// When the next pin is due, if any are waiting
+    fn next_due(&self) -> Option<Instant> {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .values()
+            .filter(|it| !it.in_flight)
+            .map(|it| it.due)
+            .min()
+    }
+
+    /// Claim every pin that `which` picks out, along with how many times each has failed
+    /// so far and whether to redo it from scratch
+    fn take_due(&self, which: Take) -> Vec<(PinKey, u32, Option<Rerender>)> {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        let mut due = Vec::new();
+        for (key, it) in pending.iter_mut() {
+            let ready = match which {
+                Take::Due => it.due <= now,
+                Take::Updates => it.rerender.is_none(),
+                Take::Everything => true,
+            };
+            if !it.in_flight && ready {
+                it.in_flight = true;
+                due.push((*key, it.attempts, it.rerender));
+            }
+        }
+        // Oldest messages first
+        due.sort_by_key(|(key, _, _)| key.message);
+        due
+    }
+
+    /// The worker's done with a pin, one way or another
+    fn finish(
+        &self,
+        key: PinKey,
+        attempts: u32,
+        rerendered: Option<Rerender>,
+        result: Result<(), anyhow::Error>,
+    ) {
+        let mut pending = self.pending.lock().unwrap();
+        let it = match pending.get_mut(&key) {
+            Some(it) => it,
+            None => return,
+        };
+        it.in_flight = false;
+
+        match result {
+            Ok(()) if it.dirty => {
+                it.dirty = false;
+                it.update = true;
+                it.attempts = 0;
+                if rerendered.is_some() && it.rerender == rerendered {
+                    // That's done, it only needs the latest count now
+                    it.rerender = None;
+                }
+                it.due = Instant::now() + PinQueue::DEBOUNCE;
+            }
+            Ok(()) => {
+                pending.remove(&key);
+                self.unsaved.store(true, Ordering::SeqCst);
+                if rerendered.is_some() && !pending.values().any(|it| it.rerender.is_some()) {
+                    log::info!("Finished redoing pins");
+                }
+            }
+            Err(oh_no) => {
+                // Never give up, or the pin would be stuck showing a stale count.
+                // Pins that are really gone don't end up here.
+                let attempts = attempts.saturating_add(1);
+                let backoff = PinQueue::RETRY_BASE
+                    .checked_mul(2u32.saturating_pow(attempts - 1))
+                    .unwrap_or(PinQueue::RETRY_MAX)
+                    .min(PinQueue::RETRY_MAX);
+                if attempts >= PinQueue::LOUD_ATTEMPTS {
+                    log::error!(
+                        "Updating the pin for message {} keeps failing ({} tries), retrying in {:?}: {:?}",
+                        key.message,
+                        attempts,
+                        backoff,
+                        oh_no
+                    );
+                } else {
+                    log::warn!(
+                        "Updating the pin for message {} failed (try {}), retrying in {:?}: {:?}",
+                        key.message,
+                        attempts,
+                        backoff,
+                        oh_no
+                    );
+                }
+                it.dirty = false;
+                it.attempts = attempts;
+                it.due = Instant::now() + backoff;
+            }
+        }
+    }
+
+    /// Write the queue out if it's changed since last time.
+    /// The write happens off the async threads, so a slow disk doesn't hold up events.
+    async fn save_if_changed(&self) {
+        let _saving = self.saving.lock().await;
+        if !self.unsaved.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let saved = {
+            let pending = self.pending.lock().unwrap();
+            pending
+                .iter()
+                .map(|(key, it)| SavedPin {
+                    key: *key,
+                    rerender: it.rerender.is_some(),
+                    how: it.rerender.unwrap_or_default(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let storage = Arc::clone(&self.storage);
+        let res = match serde_json::to_string(&saved) {
+            Ok(json) => {
+                tokio::task::spawn_blocking(move || storage.write(PinQueue::SAVE_NAME, &json))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|res| res)
+            }
+            Err(oh_no) => Err(oh_no.into()),
+        };
+        if let Err(oh_no) = res {
+            log::error!("Couldn't save the pin queue: {:?}", oh_no);
+            // Try again next time round
+            self.unsaved.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Which queued pins to work through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Take {
+    /// Only the ones that have come due
+    Due,
+    /// Every count update, without waiting.
+    /// Pins waiting to be redone stay saved and pick up where they left off next time,
+    /// rather than all going at once.
+    Updates,
+    /// All of them, rerenders included
+    Everything,
+}
+
+/// Update every pin that `which` picks out, one at a time
+pub(super) async fn process<D: Discord + ?Sized>(
+    queue: &PinQueue,
+    guilds: &RwLock<HashMap<GuildId, Arc<GuildState>>>,
+    discord: &D,
+    which: Take,
+) {
+    for (key, attempts, rerender) in queue.take_due(which) {
+        let state = guilds.read().await.get(&key.guild).cloned();
+        let result = match state {
+            Some(state) => pins::update_pin_message(&state, discord, key, rerender).await,
+            // We must have been removed from the guild
+            None => Ok(()),
+        };
+        queue.finish(key, attempts, rerender, result);
+    }
+    queue.save_if_changed().await;
+}
+
+/// Keep working through the queue, forever
+pub(super) async fn run(
+    queue: Arc<PinQueue>,
+    guilds: Arc<RwLock<HashMap<GuildId, Arc<GuildState>>>>,
+    discord: Arc<dyn Discord>,
+) {
+    loop {
+        match queue.next_due() {
+            Some(due) => {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(due) => {}
+                    _ = queue.wake.notified() => {}
+                }
+            }
+            None => queue.wake.notified().await,
+        }
+        process(&queue, &guilds, &*discord, Take::Due).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn key(message: u64) -> PinKey {
+        PinKey {
+            guild: GuildId(10),
+            channel: ChannelId(20),
+            message: MessageId(message),
+        }
+    }
+
+    fn queue() -> PinQueue {
+        PinQueue::load(Arc::new(MemoryStorage::default()))
+    }
+
+    #[test]
+    fn bursts_coalesce_into_one_update() {
+        let queue = queue();
+        for _ in 0..5 {
+            queue.push(key(1));
+        }
+        queue.push(key(2));
+        let due = queue.take_due(Take::Everything);
+        assert_eq!(
+            due.iter()
+                .map(|(key, _, _)| key.message)
+                .collect::<Vec<_>>(),
+            vec![MessageId(1), MessageId(2)]
+        );
+        // Nothing's due until the debounce is up
+        queue.push(key(3));
+        assert!(queue.take_due(Take::Due).is_empty());
+    }
+
+    #[test]
+    fn changes_while_in_flight_get_another_go() {
+        let queue = queue();
+        queue.push(key(1));
+        let due = queue.take_due(Take::Everything);
+        assert_eq!(due.len(), 1);
+        queue.push(key(1));
+        queue.finish(key(1), 0, None, Ok(()));
+        assert_eq!(queue.take_due(Take::Everything).len(), 1);
+        queue.finish(key(1), 0, None, Ok(()));
+        assert!(queue.take_due(Take::Everything).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failures_back_off_and_keep_retrying() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let queue = PinQueue::load(Arc::clone(&storage));
+        queue.push(key(1));
+        for attempts in 1..PinQueue::LOUD_ATTEMPTS + 5 {
+            let due = queue.take_due(Take::Everything);
+            assert_eq!(due.len(), 1);
+            let (_, tried, _) = due[0];
+            assert_eq!(tried, attempts - 1);
+            let before = Instant::now();
+            queue.finish(key(1), tried, None, Err(anyhow::anyhow!("nope")));
+            let pending = queue.pending.lock().unwrap();
+            let it = &pending[&key(1)];
+            let wait = it.due - before;
+            let expected = (PinQueue::RETRY_BASE * 2u32.pow(attempts - 1)).min(PinQueue::RETRY_MAX);
+            assert!(wait >= expected, "{:?} < {:?}", wait, expected);
+            assert!(wait <= PinQueue::RETRY_MAX + Duration::from_secs(1));
+        }
+        // Still there to try again, and saved for after a restart
+        assert!(queue.pending.lock().unwrap().contains_key(&key(1)));
+        queue.save_if_changed().await;
+        let queue = PinQueue::load(storage);
+        assert!(queue.pending.lock().unwrap().contains_key(&key(1)));
+    }
+
+    #[tokio::test]
+    async fn rerenders_resume_after_a_restart() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let queue = PinQueue::load(Arc::clone(&storage));
+        queue.push(key(1));
+        queue.push_rerenders([key(2), key(3)], Rerender::default());
+        queue.save_if_changed().await;
+
+        let queue = PinQueue::load(storage);
+        assert_eq!(queue.rerenders_left(GuildId(10)), 2);
+        assert_eq!(queue.pending.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn flushing_leaves_rerenders_queued() {
+        let queue = queue();
+        queue.push(key(1));
+        queue.push_rerenders([key(2), key(3)], Rerender::default());
+        let due = queue.take_due(Take::Updates);
+        assert_eq!(
+            due.iter()
+                .map(|(key, _, _)| key.message)
+                .collect::<Vec<_>>(),
+            vec![MessageId(1)]
+        );
+        assert_eq!(queue.rerenders_left(GuildId(10)), 2);
+    }
+}
@@ -0,0 +1,1333 @@
+//! Keeps pin messages in step with the taters on the messages they're for
+
+use std::{collections::hash_map, sync::Arc};
+
+use anyhow::{anyhow, bail};
+use serenity::model::id::{ChannelId, GuildId, MessageId, WebhookId};
+
+use super::{
+    pin_queue::{PinKey, Rerender},
+    template::{DeletedPins, NsfwPins, PinInfo, PinMode, PinTemplate},
+    GuildState, Handler, HandlerWrapper, PinOverride, TateredMessage,
+};
+use crate::{
+    discord::{is_not_found, Discord, PinEmbed, PinMessage, PinWebhook, SourceMessage, WebhookPin},
+    metrics::{PinAction, METRICS},
+};
+
+/// Everything needed to post a pin that isn't there yet
+struct NewPin {
+    pin_channel: ChannelId,
+    info: PinInfo,
+    template: PinTemplate,
+    show_reply: bool,
+    mode: PinMode,
+    /// The webhook to send it through, if there is one yet
+    webhook: Option<PinWebhook>,
+    /// What to do if the message turns out to be from an NSFW channel
+    nsfw: NsfwPins,
+}
+
+impl NewPin {
+    /// Follow the guild's NSFW policy, if `message` is from an NSFW channel.
+    /// None if it shouldn't be pinned.
+    fn for_message(mut self, message: MessageId, nsfw: bool) -> Option<Self> {
+        if !nsfw {
+            return Some(self);
+        }
+        match self.nsfw {
+            NsfwPins::Allow | NsfwPins::Spoiler => {}
+            NsfwPins::Never => {
+                log::debug!("Not pinning message {}, it's from an NSFW channel", message);
+                return None;
+            }
+            NsfwPins::Board(channel) => {
+                self.pin_channel = channel;
+                self.mode = PinMode::Embed;
+                self.webhook = None;
+            }
+        }
+        Some(self)
+    }
+}
+
+/// What needs doing to a message's pin, worked out from the handler
+/// so it can be done without holding the handler locked
+enum PinPlan {
+    /// Nothing's pinned and nothing should be
+    Nothing,
+    /// It fell under the threshold, so take the pin down
+    Delete {
+        pin_channel: ChannelId,
+        pin: MessageId,
+        /// The webhook that sent it, if it was one we still have
+        webhook: Option<PinWebhook>,
+    },
+    /// Redo the whole pin, moving or deleting it if the NSFW policy says so
+    Replace {
+        pin_channel: ChannelId,
+        pin: MessageId,
+        new: NewPin,
+    },
+    /// Just the header needs changing
+    Edit {
+        pin_channel: ChannelId,
+        pin: MessageId,
+        content: String,
+    },
+    /// Just the footer of a webhook pin needs changing
+    EditWebhook {
+        webhook: PinWebhook,
+        pin: MessageId,
+        footer: PinEmbed,
+    },
+    /// It's newly over the threshold
+    Create(NewPin),
+    /// It's pinned in an old pin channel, so post it again in the current one
+    Move {
+        old_channel: ChannelId,
+        old_pin: MessageId,
+        /// The webhook that sent the old pin, if it was one we still have
+        old_webhook: Option<PinWebhook>,
+        delete_old: bool,
+        new: NewPin,
+        /// Post it again even if it'd end up in the same channel, like to redo a webhook pin,
+        /// which can't have its name, avatar, or files changed
+        repost: bool,
+    },
+}
+
+/// Something a moderator can do about a message's pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Moderation {
+    /// Pin it, whatever its count
+    Force,
+    /// Take it down and keep it off the board
+    Unpin,
+    /// Let its count decide again
+    Allow,
+    /// Stop counting taters on it
+    Lock,
+    /// Start counting them again
+    Unlock,
+}
+
+impl Handler {
+    /// Whether a message should be pinned, going by its count unless a moderator said otherwise.
+    /// Messages too old for new pins only stay pinned if they already are.
+    fn should_pin(&self, message: MessageId, tatered_message: &TateredMessage) -> bool {
+        match tatered_message.pin_override {
+            Some(PinOverride::Force) => true,
+            Some(PinOverride::Block) => false,
+            None => {
+                self.medal(tatered_message.count).is_some()
+                    && (tatered_message.pin_id.is_some() || !self.config.too_old(message))
+            }
+        }
+    }
+
+    /// The medal for this many taters, or None if it's not enough to be pinned
+    fn medal(&self, count: u64) -> Option<&str> {
+        let medal_idx = (count as f32 / self.config.threshold as f32).log2().floor();
+        if medal_idx >= 0.0 {
+            // we made it, nice
+            Some(match self.config.medals.get(medal_idx as usize) {
+                Some(it) => it.as_str(),
+                None => self.config.medals.last().map(|s| s.as_str()).unwrap_or("?"),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// What the placeholders in a pin of this message would stand for
+    fn pin_info(
+        &self,
+        guild: GuildId,
+        channel: ChannelId,
+        message: MessageId,
+        tatered_message: &TateredMessage,
+    ) -> PinInfo {
+        let previous_pins = self
+            .tatered_messages
+            .iter()
+            // Snowflakes go up over time, so lower IDs came first
+            .filter(|(id, msg)| **id < message && msg.sender == tatered_message.sender)
+            .count();
+        // Forced pins get the lowest medal until they earn one
+        let medal = match self.medal(tatered_message.count) {
+            Some(it) => it,
+            None if tatered_message.pin_override == Some(PinOverride::Force) => self
+                .config
+                .medals
+                .first()
+                .map(|s| s.as_str())
+                .unwrap_or("?"),
+            None => "",
+        };
+        PinInfo {
+            medal: medal.to_owned(),
+            count: tatered_message.count,
+            author: tatered_message.sender,
+            guild,
+            channel,
+            message,
+            previous_pins,
+            givers: tatered_message.givers.clone(),
+        }
+    }
+
+    /// A pin of this message as it'd be posted now, in the current pin channel
+    fn new_pin(&self, info: PinInfo) -> NewPin {
+        let pin_channel = self.config.pin_channel;
+        NewPin {
+            pin_channel,
+            info,
+            template: self.config.pin_template.clone(),
+            show_reply: self.config.show_replies,
+            mode: self.config.pin_mode,
+            webhook: self
+                .config
+                .pin_webhook
+                .clone()
+                .filter(|webhook| webhook.channel == pin_channel),
+            nsfw: self.config.nsfw_pins,
+        }
+    }
+
+    /// Work out what should happen to a message's pin, redoing all of it if `rerender`.
+    /// Returns None if we don't know the message.
+    fn plan_pin(&self, key: PinKey, rerender: Option<Rerender>) -> Option<PinPlan> {
+        let PinKey {
+            guild,
+            channel,
+            message,
+        } = key;
+        let tatered_message = self.tatered_messages.get(&message)?;
+        let pin_channel = tatered_message
+            .pin_channel
+            .unwrap_or(self.config.pin_channel);
+
+        // Webhooks can only touch their own messages
+        let pin_webhook = tatered_message
+            .pin_webhook
+            .and_then(|id| self.config.webhook(id).cloned());
+
+        if !self.should_pin(message, tatered_message) {
+            // oh no we gotta delete that now ;-;
+            return Some(match tatered_message.pin_id {
+                Some(pin) => PinPlan::Delete {
+                    pin_channel,
+                    pin,
+                    webhook: pin_webhook,
+                },
+                None => PinPlan::Nothing,
+            });
+        }
+
+        let info = self.pin_info(guild, channel, message, tatered_message);
+        let template = &self.config.pin_template;
+        let plan = match (
+            tatered_message.pin_id,
+            tatered_message.pin_webhook,
+            rerender,
+        ) {
+            (Some(old_pin), _, Some(how))
+                if how.migrate
+                    && (pin_channel != self.config.pin_channel
+                        || matches!(self.config.nsfw_pins, NsfwPins::Board(_))) =>
+            {
+                PinPlan::Move {
+                    old_channel: pin_channel,
+                    old_pin,
+                    old_webhook: pin_webhook,
+                    delete_old: how.delete_old,
+                    new: self.new_pin(info),
+                    repost: false,
+                }
+            }
+            (Some(old_pin), Some(_), Some(_)) => PinPlan::Move {
+                old_channel: pin_channel,
+                old_pin,
+                old_webhook: pin_webhook,
+                delete_old: true,
+                new: self.new_pin(info),
+                repost: true,
+            },
+            (Some(pin), None, Some(_)) => PinPlan::Replace {
+                pin_channel,
+                pin,
+                new: self.new_pin(info),
+            },
+            (Some(pin), None, None) => PinPlan::Edit {
+                pin_channel,
+                pin,
+                content: info.fill(&template.header),
+            },
+            (Some(pin), Some(_), None) => match pin_webhook {
+                Some(webhook) => PinPlan::EditWebhook {
+                    webhook,
+                    pin,
+                    footer: webhook_footer(template, &info),
+                },
+                None => {
+                    log::debug!("Can't update pin {}, the webhook that sent it is gone", pin);
+                    PinPlan::Nothing
+                }
+            },
+            (None, _, _) => PinPlan::Create(self.new_pin(info)),
+        };
+        Some(plan)
+    }
+
+    /// Someone else deleted a message's pin. Forget it, then either keep the message
+    /// off the board or have it pinned again, as the guild likes.
+    /// Returns whether it should be pinned again.
+    fn forget_pin(&mut self, message: MessageId) -> bool {
+        let deleted_pins = self.config.deleted_pins;
+        let tatered_message = match self.tatered_messages.get_mut(&message) {
+            Some(it) => it,
+            None => return false,
+        };
+        tatered_message.pin_id = None;
+        tatered_message.pin_channel = None;
+        tatered_message.pin_webhook = None;
+        match deleted_pins {
+            DeletedPins::Clear => {
+                tatered_message.pin_override = Some(PinOverride::Block);
+                false
+            }
+            DeletedPins::Recreate => true,
+        }
+    }
+}
+
+/// The messages whose pins are among `deleted`
+fn pins_in(this: &Handler, channel: ChannelId, deleted: &[MessageId]) -> Vec<MessageId> {
+    this.tatered_messages
+        .iter()
+        .filter(|(_, msg)| {
+            msg.pin_channel.unwrap_or(this.config.pin_channel) == channel
+                && msg.pin_id.is_some_and(|pin| deleted.contains(&pin))
+        })
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+/// How carrying out a [`PinPlan`] went
+enum Outcome {
+    Done,
+    /// The pin we meant to change was deleted by someone else
+    PinGone,
+}
+
+/// Bring the pin for a message up to date with its tater count.
+/// With `rerender`, redo all of it with the guild's current settings.
+pub(super) async fn update_pin_message<D: Discord + ?Sized>(
+    state: &GuildState,
+    discord: &D,
+    key: PinKey,
+    rerender: Option<Rerender>,
+) -> Result<(), anyhow::Error> {
+    let _syncing = state.pin_sync.lock().await;
+    loop {
+        // Plan against the latest count, which might have moved on while we waited
+        let plan = match state.lock().await.plan_pin(key, rerender) {
+            Some(it) => it,
+            None => return Ok(()),
+        };
+        match carry_out(state, discord, key, plan).await? {
+            Outcome::Done => return Ok(()),
+            Outcome::PinGone => {
+                log::info!("The pin for message {} was deleted", key.message);
+                // Once it's forgotten there's no pin left to go missing, so this only loops once
+                if !state.lock().await.forget_pin(key.message) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn carry_out<D: Discord + ?Sized>(
+    state: &GuildState,
+    discord: &D,
+    key: PinKey,
+    plan: PinPlan,
+) -> Result<Outcome, anyhow::Error> {
+    let (new_pin_id, new_pin_channel, new_pin_webhook) = match plan {
+        PinPlan::Nothing => return Ok(Outcome::Done),
+        PinPlan::Delete {
+            pin_channel,
+            pin,
+            webhook,
+        } => {
+            take_down(discord, pin_channel, pin, webhook.as_ref()).await?;
+            (None, None, None)
+        }
+        PinPlan::Replace {
+            pin_channel,
+            pin,
+            new,
+        } => {
+            log::trace!("Redoing pin message {}", pin);
+            let original_message = match original_message(discord, key).await? {
+                Some(it) => it,
+                None => return Ok(Outcome::Done),
+            };
+            let nsfw = nsfw_channel(discord, key.channel, new.nsfw).await;
+            match new.for_message(key.message, nsfw) {
+                None => {
+                    take_down(discord, pin_channel, pin, None).await?;
+                    (None, None, None)
+                }
+                // Off to the NSFW board
+                Some(new) if nsfw && new.pin_channel != pin_channel => {
+                    let old = Some((pin_channel, pin, None));
+                    let sent =
+                        send_replacement(state, discord, &original_message, new, nsfw, old).await?;
+                    METRICS.pin(PinAction::Moved);
+                    sent
+                }
+                Some(new) => {
+                    let spoil = new.nsfw == NsfwPins::Spoiler && nsfw;
+                    let new_pin = render_pin(
+                        &new.template,
+                        &new.info,
+                        &original_message,
+                        new.show_reply,
+                        spoil,
+                    );
+                    return edited(discord.edit_pin(pin_channel, pin, &new_pin).await);
+                }
+            }
+        }
+        PinPlan::Edit {
+            pin_channel,
+            pin,
+            content,
+        } => {
+            log::trace!("Editing existing pin message {}", pin);
+            // we just need to edit the header
+            return edited(discord.edit_pin_header(pin_channel, pin, &content).await);
+        }
+        PinPlan::EditWebhook {
+            webhook,
+            pin,
+            footer,
+        } => {
+            log::trace!("Editing existing webhook pin {}", pin);
+            return edited(discord.edit_webhook_pin(&webhook, pin, &footer).await);
+        }
+        PinPlan::Create(new) => {
+            log::trace!("Creating new pin message");
+            let original_message = match original_message(discord, key).await? {
+                Some(it) => it,
+                None => return Ok(Outcome::Done),
+            };
+            let nsfw = nsfw_channel(discord, key.channel, new.nsfw).await;
+            let new = match new.for_message(key.message, nsfw) {
+                Some(it) => it,
+                None => return Ok(Outcome::Done),
+            };
+            let pin_channel = new.pin_channel;
+            let (pin_id, pin_webhook) =
+                send_pin(state, discord, &original_message, new, nsfw).await?;
+            METRICS.pin(PinAction::Created);
+            (Some(pin_id), Some(pin_channel), pin_webhook)
+        }
+        PinPlan::Move {
+            old_channel,
+            old_pin,
+            old_webhook,
+            delete_old,
+            new,
+            repost,
+        } => {
+            log::trace!("Moving pin message {} out of {}", old_pin, old_channel);
+            let original_message = match original_message(discord, key).await? {
+                Some(it) => it,
+                None => return Ok(Outcome::Done),
+            };
+            let nsfw = nsfw_channel(discord, key.channel, new.nsfw).await;
+            match new.for_message(key.message, nsfw) {
+                Some(new) if repost || new.pin_channel != old_channel => {
+                    let old = Some((old_channel, old_pin, old_webhook)).filter(|_| delete_old);
+                    let sent =
+                        send_replacement(state, discord, &original_message, new, nsfw, old).await?;
+                    METRICS.pin(if repost {
+                        PinAction::Edited
+                    } else {
+                        PinAction::Moved
+                    });
+                    sent
+                }
+                // Already where it belongs
+                Some(_) => return Ok(Outcome::Done),
+                // Not meant to be pinned any more, so it goes if the old one's going anyway
+                None if delete_old => {
+                    take_down(discord, old_channel, old_pin, old_webhook.as_ref()).await?;
+                    (None, None, None)
+                }
+                None => return Ok(Outcome::Done),
+            }
+        }
+    };
+
+    if let Some(tm) = state.lock().await.tatered_messages.get_mut(&key.message) {
+        tm.pin_id = new_pin_id;
+        tm.pin_channel = new_pin_channel;
+        tm.pin_webhook = new_pin_webhook;
+    }
+    Ok(Outcome::Done)
+}
+
+/// Take a pin down, through the webhook that sent it if we still have it.
+/// It being gone already is fine.
+async fn take_down<D: Discord + ?Sized>(
+    discord: &D,
+    pin_channel: ChannelId,
+    pin: MessageId,
+    webhook: Option<&PinWebhook>,
+) -> Result<(), anyhow::Error> {
+    let deleted = match webhook {
+        Some(webhook) => discord.delete_webhook_pin(webhook, pin).await,
+        // If it was sent through a webhook we've lost, this still works
+        // as long as we can manage messages
+        None => discord.delete_message(pin_channel, pin).await,
+    };
+    match deleted {
+        Ok(()) => METRICS.pin(PinAction::Deleted),
+        // Someone beat us to it
+        Err(oh_no) if is_not_found(&oh_no) => {}
+        Err(oh_no) => return Err(oh_no),
+    }
+    Ok(())
+}
+
+/// Post a pin in place of `old`, then delete `old` if there is one.
+/// Returns the new pin's ID, channel, and webhook, for the message to remember.
+async fn send_replacement<D: Discord + ?Sized>(
+    state: &GuildState,
+    discord: &D,
+    original_message: &SourceMessage,
+    new: NewPin,
+    nsfw_channel: bool,
+    old: Option<(ChannelId, MessageId, Option<PinWebhook>)>,
+) -> Result<(Option<MessageId>, Option<ChannelId>, Option<WebhookId>), anyhow::Error> {
+    let pin_channel = new.pin_channel;
+    let (pin_id, pin_webhook) =
+        send_pin(state, discord, original_message, new, nsfw_channel).await?;
+    if let Some((old_channel, old_pin, old_webhook)) = old {
+        // The new pin's already up, so failing now mustn't make us post it again
+        let deleted = match old_webhook {
+            Some(webhook) => discord.delete_webhook_pin(&webhook, old_pin).await,
+            None => discord.delete_message(old_channel, old_pin).await,
+        };
+        if let Err(oh_no) = deleted {
+            log::warn!("Couldn't delete old pin {}: {:?}", old_pin, oh_no);
+        }
+    }
+    Ok((Some(pin_id), Some(pin_channel), pin_webhook))
+}
+
+/// Fetch the message a pin is for. None if it's been deleted, since then there's nothing to
+/// pin, and its old pin is best left as it is.
+async fn original_message<D: Discord + ?Sized>(
+    discord: &D,
+    key: PinKey,
+) -> Result<Option<SourceMessage>, anyhow::Error> {
+    match discord.get_message(key.channel, key.message).await {
+        Ok(it) => Ok(Some(it)),
+        Err(oh_no) if is_not_found(&oh_no) => {
+            log::debug!(
+                "Message {} was deleted, so its pin stays as it is",
+                key.message
+            );
+            Ok(None)
+        }
+        Err(oh_no) => Err(oh_no),
+    }
+}
+
+/// Whether a message in `channel` counts as NSFW under the guild's policy.
+/// Nothing does when NSFW channels are allowed, so Discord isn't asked.
+/// If Discord won't say, the channel's taken not to be, so the tater still counts.
+async fn nsfw_channel<D: Discord + ?Sized>(
+    discord: &D,
+    channel: ChannelId,
+    policy: NsfwPins,
+) -> bool {
+    if policy == NsfwPins::Allow {
+        return false;
+    }
+    match discord.is_nsfw(channel).await {
+        Ok(it) => it,
+        Err(oh_no) => {
+            log::warn!(
+                "Couldn't tell whether channel {} is NSFW, going with no: {:?}",
+                channel,
+                oh_no
+            );
+            false
+        }
+    }
+}
+
+/// How editing a pin went
+fn edited(result: Result<(), anyhow::Error>) -> Result<Outcome, anyhow::Error> {
+    match result {
+        Ok(()) => {
+            METRICS.pin(PinAction::Edited);
+            Ok(Outcome::Done)
+        }
+        Err(oh_no) if is_not_found(&oh_no) => Ok(Outcome::PinGone),
+        Err(oh_no) => Err(oh_no),
+    }
+}
+
+/// Post a new pin, making a webhook for it first if it needs one.
+/// `nsfw` is whether the message is from an NSFW channel.
+/// Returns the pin's ID and which webhook sent it, if one did.
+async fn send_pin<D: Discord + ?Sized>(
+    state: &GuildState,
+    discord: &D,
+    original_message: &SourceMessage,
+    new: NewPin,
+    nsfw_channel: bool,
+) -> Result<(MessageId, Option<WebhookId>), anyhow::Error> {
+    let NewPin {
+        pin_channel,
+        info,
+        template,
+        show_reply,
+        mode,
+        webhook,
+        nsfw,
+    } = new;
+    let spoil = nsfw == NsfwPins::Spoiler && nsfw_channel;
+    match mode {
+        PinMode::Embed => {
+            let pin = render_pin(&template, &info, original_message, show_reply, spoil);
+            Ok((discord.send_pin(pin_channel, &pin).await?, None))
+        }
+        PinMode::Webhook => {
+            let webhook = match webhook {
+                Some(it) => it,
+                None => {
+                    let webhook = discord.create_webhook(pin_channel).await?;
+                    let mut this = state.lock().await;
+                    // Keep the old one, to update and delete what it already sent
+                    if let Some(old) = this.config.pin_webhook.replace(webhook.clone()) {
+                        this.config.old_pin_webhooks.push(old);
+                    }
+                    webhook
+                }
+            };
+            let pin = render_webhook_pin(&template, &info, original_message, show_reply, spoil);
+            match discord.send_webhook_pin(&webhook, &pin).await {
+                Ok(pin_id) => Ok((pin_id, Some(webhook.id))),
+                Err(oh_no) => {
+                    if is_not_found(&oh_no) {
+                        // Someone deleted it, so make a new one next try
+                        let mut this = state.lock().await;
+                        if this.config.pin_webhook.as_ref() == Some(&webhook) {
+                            this.config.pin_webhook = None;
+                        }
+                    }
+                    Err(oh_no)
+                }
+            }
+        }
+    }
+}
+
+impl HandlerWrapper {
+    /// Queue every pin in a guild to be redone with its current settings.
+    /// Returns how many were queued, and how many are too old to redo
+    /// because we never learned which channel their message is in.
+    pub async fn rerender_pins(&self, guild: GuildId) -> (usize, usize) {
+        let (keys, unknown) = self.pinned(guild, |_, _| true).await;
+        (
+            self.pin_queue.push_rerenders(keys, Rerender::default()),
+            unknown,
+        )
+    }
+
+    /// Queue every pin that isn't in the current pin channel to be posted again there,
+    /// or in the NSFW board if it has one and the pin's from an NSFW channel,
+    /// deleting the old ones as it goes if `delete_old`. Returns the same as `rerender_pins`.
+    pub async fn migrate_pins(&self, guild: GuildId, delete_old: bool) -> (usize, usize) {
+        let (keys, unknown) = self
+            .pinned(guild, |this, msg| match this.config.nsfw_pins {
+                // Any pin on the main board might be from an NSFW channel
+                NsfwPins::Board(board) => msg.pin_channel != Some(board),
+                _ => msg.pin_channel != Some(this.config.pin_channel),
+            })
+            .await;
+        let how = Rerender {
+            migrate: true,
+            delete_old,
+        };
+        (self.pin_queue.push_rerenders(keys, how), unknown)
+    }
+
+    /// The guild's pinned messages that `filter` picks, oldest first, so a board that's
+    /// half done is done from the top. Also returns how many were picked but are too old
+    /// to be redone, because we never learned which channel their message is in.
+    async fn pinned(
+        &self,
+        guild: GuildId,
+        filter: impl Fn(&Handler, &TateredMessage) -> bool,
+    ) -> (Vec<PinKey>, usize) {
+        let state = self.guild(guild).await;
+        let this = state.lock().await;
+        let mut keys = Vec::new();
+        let mut unknown = 0;
+        for (message, tatered_message) in &this.tatered_messages {
+            if tatered_message.pin_id.is_none() || !filter(&this, tatered_message) {
+                continue;
+            }
+            match tatered_message.channel {
+                Some(channel) => keys.push(PinKey {
+                    guild,
+                    channel,
+                    message: *message,
+                }),
+                None => unknown += 1,
+            }
+        }
+        keys.sort_by_key(|key| key.message);
+        (keys, unknown)
+    }
+
+    /// How many of a guild's pins are still waiting to be redone
+    pub fn rerenders_left(&self, guild: GuildId) -> usize {
+        self.pin_queue.rerenders_left(guild)
+    }
+
+    /// Stop redoing a guild's pins. Returns how many were left.
+    pub fn cancel_rerenders(&self, guild: GuildId) -> usize {
+        self.pin_queue.cancel_rerenders(guild)
+    }
+
+    /// Messages were deleted. If any were pins and we didn't delete them ourselves,
+    /// forget them, and queue them to be posted again if the guild wants that.
+    pub async fn on_messages_deleted(
+        &self,
+        guild: GuildId,
+        channel: ChannelId,
+        deleted: &[MessageId],
+    ) {
+        let state = match self.guilds.read().await.get(&guild) {
+            Some(it) => Arc::clone(it),
+            None => return,
+        };
+        // Most deleted messages aren't pins, so check before waiting on any pin updates
+        if pins_in(&*state.lock().await, channel, deleted).is_empty() {
+            return;
+        }
+        // If we're deleting a pin ourselves, that finishes first and it won't show up here
+        let _syncing = state.pin_sync.lock().await;
+        let recreate = {
+            let mut this = state.lock().await;
+            let mut recreate = Vec::new();
+            for message in pins_in(&this, channel, deleted) {
+                log::info!("The pin for message {} was deleted", message);
+                if !this.forget_pin(message) {
+                    continue;
+                }
+                match this.tatered_messages[&message].channel {
+                    Some(channel) => recreate.push(PinKey {
+                        guild,
+                        channel,
+                        message,
+                    }),
+                    None => log::warn!(
+                        "Can't pin message {} again, we don't know which channel it's in",
+                        message
+                    ),
+                }
+            }
+            recreate
+        };
+        for key in recreate {
+            self.pin_queue.push(key);
+        }
+    }
+
+    /// Do what a moderator asked to a message's pin, and queue the pin to catch up.
+    /// Returns the message's tater count.
+    pub(super) async fn moderate_pin<D: Discord + ?Sized>(
+        &self,
+        discord: &D,
+        key: PinKey,
+        moderation: Moderation,
+    ) -> Result<u64, anyhow::Error> {
+        let state = self.guild(key.guild).await;
+        // Only ask Discord who sent it if we don't know yet
+        let cached = state
+            .lock()
+            .await
+            .tatered_messages
+            .contains_key(&key.message);
+        let sender = if cached {
+            None
+        } else {
+            let author = discord.get_author(key.channel, key.message).await?;
+            if Some(author.author_id) == self.bot_uid().await {
+                bail!("That's one of my messages");
+            }
+            Some(author.author_id)
+        };
+
+        let count = {
+            let mut this = state.lock().await;
+            let tatered_message = match this.tatered_messages.entry(key.message) {
+                hash_map::Entry::Occupied(o) => o.into_mut(),
+                hash_map::Entry::Vacant(v) => {
+                    let sender =
+                        sender.ok_or_else(|| anyhow!("That message was just forgotten"))?;
+                    v.insert(TateredMessage::new(sender, 0, None))
+                }
+            };
+            tatered_message.channel.get_or_insert(key.channel);
+            match moderation {
+                Moderation::Force => tatered_message.pin_override = Some(PinOverride::Force),
+                Moderation::Unpin => tatered_message.pin_override = Some(PinOverride::Block),
+                Moderation::Allow => tatered_message.pin_override = None,
+                Moderation::Lock => tatered_message.locked = true,
+                Moderation::Unlock => tatered_message.locked = false,
+            }
+            tatered_message.count
+        };
+        self.pin_queue.push(key);
+        Ok(count)
+    }
+
+    /// Lay out what a pin of the given message would look like with the guild's template,
+    /// without sending it. Messages without enough taters are shown as if they just made it.
+    pub async fn preview_pin<D: Discord + ?Sized>(
+        &self,
+        discord: &D,
+        guild: GuildId,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> Result<PinMessage, anyhow::Error> {
+        let original_message = discord.get_message(channel, message).await?;
+        let state = self.guild(guild).await;
+        let nsfw_pins = state.lock().await.config.nsfw_pins;
+        let spoil =
+            nsfw_pins == NsfwPins::Spoiler && nsfw_channel(discord, channel, nsfw_pins).await;
+        let this = state.lock().await;
+        let mut tatered_message = match this.tatered_messages.get(&message) {
+            Some(it) => it.clone(),
+            None => TateredMessage::new(original_message.author_id, 0, None),
+        };
+        tatered_message.count = tatered_message.count.max(this.config.threshold);
+        let info = this.pin_info(guild, channel, message, &tatered_message);
+        Ok(render_pin(
+            &this.config.pin_template,
+            &info,
+            &original_message,
+            this.config.show_replies,
+            spoil,
+        ))
+    }
+}
+
+/// Discord won't take more embeds than this on one message
+const MAX_EMBEDS: usize = 10;
+/// Discord only shows this many images in a gallery
+const MAX_GALLERY: usize = 4;
+/// Discord won't take a longer embed field value
+const MAX_FIELD_LEN: usize = 1024;
+/// Discord won't take a longer embed footer
+const MAX_FOOTER_LEN: usize = 2048;
+/// Discord won't take a longer embed field name
+const MAX_FIELD_NAME_LEN: usize = 256;
+/// How much of a replied-to message to quote
+const MAX_REPLY_LEN: usize = 200;
+/// Discord won't take a longer embed description
+const MAX_DESCRIPTION_LEN: usize = 4096;
+/// Discord won't take longer message content
+const MAX_CONTENT_LEN: usize = 2000;
+/// Discord won't take a longer webhook username
+const MAX_USERNAME_LEN: usize = 80;
+/// Discord won't take more files on one message
+const MAX_FILES: usize = 10;
+/// Biggest file we'll upload again. Boosted guilds take bigger ones, but this always works.
+const MAX_UPLOAD: u64 = 8 * 1024 * 1024;
+
+/// Lay out a new pin for the given message, following the guild's template.
+///
+/// Every image goes into a gallery, anything else attached is linked by name, and if the
+/// message is only a link, its previews come along too. With `show_reply`, replies quote
+/// a bit of what they were replying to.
+///
+/// Embed images can't be blurred, so spoilered attachments are linked instead. With `spoil`,
+/// everything is hidden like that.
+fn render_pin(
+    template: &PinTemplate,
+    info: &PinInfo,
+    original_message: &SourceMessage,
+    show_reply: bool,
+    spoil: bool,
+) -> PinMessage {
+    let link = original_message.link();
+
+    let mut images = Vec::new();
+    let mut files = Vec::new();
+    for att in &original_message.attachments {
+        let spoiler = spoil || att.is_spoiler();
+        if att.is_image() && !spoiler && images.len() < MAX_GALLERY {
+            images.push(att.url.clone());
+        } else {
+            let file = format!("[{}]({})", att.filename, att.url);
+            files.push(format!(
+                "{} ({})",
+                if spoiler { spoiler_of(&file) } else { file },
+                format_size(att.size)
+            ));
+        }
+    }
+    // Images and GIFs linked in the message. If any of it's spoilered, they might be too.
+    let hides_links = spoil || original_message.content.contains("||");
+    for embed in original_message.embeds.iter().filter(|_| !hides_links) {
+        if embed.kind == "image" || embed.kind == "gifv" {
+            if let Some(image) = &embed.image {
+                if images.len() < MAX_GALLERY {
+                    images.push(image.clone());
+                }
+            }
+        }
+    }
+    for sticker in original_message.stickers.iter().filter(|_| !spoil) {
+        if let Some(image) = &sticker.image {
+            if images.len() < MAX_GALLERY {
+                images.push(image.clone());
+            }
+        }
+    }
+
+    let mut fields = Vec::new();
+    if let Some(reply) = original_message.reply_to.as_ref().filter(|_| show_reply) {
+        let quote = if reply.content.is_empty() {
+            "*No text*".to_owned()
+        } else if spoil {
+            spoiler_of(&truncate(&reply.content, MAX_REPLY_LEN))
+        } else {
+            close_spoilers(truncate(&reply.content, MAX_REPLY_LEN))
+        };
+        fields.push((
+            truncate(
+                &format!("Replying to {}", reply.author_name),
+                MAX_FIELD_NAME_LEN,
+            ),
+            format!("{}\n[Jump to it]({})", quote, reply.link),
+            false,
+        ));
+    }
+    if !files.is_empty() {
+        fields.push(("Attachments".to_owned(), join_lines(&files), false));
+    }
+    if !original_message.stickers.is_empty() {
+        let names = original_message
+            .stickers
+            .iter()
+            .map(|sticker| sticker.name.clone())
+            .collect::<Vec<_>>();
+        fields.push(("Stickers".to_owned(), join_lines(&names), false));
+    }
+    if let Some(jump) = &template.jump {
+        fields.push((
+            // zero width space
+            "\u{200b}".to_owned(),
+            truncate(&info.fill(jump), MAX_FIELD_LEN),
+            false,
+        ));
+    }
+
+    let mut images = images.into_iter();
+    let (author_name, author_icon) = if template.author {
+        (
+            original_message.author_name.clone(),
+            original_message.author_avatar.clone(),
+        )
+    } else {
+        Default::default()
+    };
+    let mut embeds = vec![PinEmbed {
+        author_name,
+        author_icon,
+        // Sharing a url with the gallery embeds is what makes Discord group them
+        url: Some(link.clone()),
+        description: if spoil {
+            spoiler_of(&original_message.content)
+        } else {
+            original_message.content.clone()
+        },
+        fields,
+        footer: template
+            .footer
+            .as_ref()
+            .map(|footer| truncate(&info.fill(footer), MAX_FOOTER_LEN)),
+        image: images.next(),
+        ..PinEmbed::default()
+    }];
+    embeds.extend(images.map(|image| PinEmbed {
+        url: Some(link.clone()),
+        image: Some(image),
+        ..PinEmbed::default()
+    }));
+
+    if !spoil && is_just_a_link(&original_message.content) {
+        let previews = original_message
+            .embeds
+            .iter()
+            .filter(|embed| embed.kind != "image" && embed.kind != "gifv")
+            .map(|embed| PinEmbed {
+                title: embed.title.clone(),
+                url: embed.url.clone(),
+                description: embed.description.clone().unwrap_or_default(),
+                image: embed.image.clone(),
+                ..PinEmbed::default()
+            });
+        embeds.extend(previews);
+    }
+    embeds.truncate(MAX_EMBEDS);
+
+    PinMessage {
+        content: info.fill(&template.header),
+        embeds,
+    }
+}
+
+/// Lay out a new pin to send through a webhook: the message again, as its author,
+/// with the header and jump link in a little embed underneath.
+///
+/// Uploads keep their names, so spoilered ones stay spoilered. With `spoil`, nothing's
+/// uploaded again and everything's hidden behind spoilers.
+fn render_webhook_pin(
+    template: &PinTemplate,
+    info: &PinInfo,
+    original_message: &SourceMessage,
+    show_reply: bool,
+    spoil: bool,
+) -> WebhookPin {
+    let mut content = String::new();
+    if let Some(reply) = original_message.reply_to.as_ref().filter(|_| show_reply) {
+        let quote = truncate(&reply.content.replace('\n', " "), MAX_REPLY_LEN);
+        let quote = if reply.content.is_empty() {
+            "*No text*".to_owned()
+        } else if spoil {
+            spoiler_of(&quote)
+        } else {
+            close_spoilers(quote)
+        };
+        content.push_str(&format!(
+            "> Replying to **{}**: {} ([jump]({}))\n",
+            reply.author_name, quote, reply.link
+        ));
+    }
+    if spoil {
+        content.push_str(&spoiler_of(&original_message.content));
+    } else {
+        content.push_str(&original_message.content);
+    }
+
+    // Upload what we can again, and link the rest
+    let mut files = Vec::new();
+    let mut leftovers = Vec::new();
+    for att in &original_message.attachments {
+        if !spoil && att.size <= MAX_UPLOAD && files.len() < MAX_FILES {
+            files.push(att.url.clone());
+        } else {
+            let file = format!("[{}]({})", att.filename, att.url);
+            leftovers.push(format!(
+                "{} ({})",
+                if spoil { spoiler_of(&file) } else { file },
+                format_size(att.size)
+            ));
+        }
+    }
+    for sticker in &original_message.stickers {
+        match &sticker.image {
+            Some(image) if !spoil && files.len() < MAX_FILES => files.push(image.clone()),
+            _ => leftovers.push(format!("*Sticker: {}*", sticker.name)),
+        }
+    }
+    for leftover in leftovers {
+        content.push('\n');
+        content.push_str(&leftover);
+    }
+
+    WebhookPin {
+        username: truncate(&original_message.author_name, MAX_USERNAME_LEN),
+        avatar_url: original_message.author_avatar.clone(),
+        content: truncate(&content, MAX_CONTENT_LEN),
+        files,
+        footer: webhook_footer(template, info),
+    }
+}
+
+/// The embed under a webhook pin. It's all that changes as taters come and go.
+fn webhook_footer(template: &PinTemplate, info: &PinInfo) -> PinEmbed {
+    let mut description = info.fill(&template.header);
+    if let Some(jump) = &template.jump {
+        description.push('\n');
+        description.push_str(&info.fill(jump));
+    }
+    PinEmbed {
+        description: truncate(&description, MAX_DESCRIPTION_LEN),
+        footer: template
+            .footer
+            .as_ref()
+            .map(|footer| truncate(&info.fill(footer), MAX_FOOTER_LEN)),
+        ..PinEmbed::default()
+    }
+}
+
+/// Whether a message is nothing but a link, so its preview is the interesting part
+fn is_just_a_link(content: &str) -> bool {
+    let content = content.trim();
+    (content.starts_with("https://") || content.starts_with("http://"))
+        && !content.contains(char::is_whitespace)
+}
+
+/// Hide all of `text` behind one spoiler. Spoilers already in it are taken out,
+/// since they'd end ours early.
+fn spoiler_of(text: &str) -> String {
+    let text = text.replace("||", "");
+    if text.trim().is_empty() {
+        text
+    } else {
+        format!("||{}||", text)
+    }
+}
+
+/// Close a spoiler that cutting `text` short left open, so the rest of it stays hidden
+fn close_spoilers(mut text: String) -> String {
+    if text.matches("||").count() % 2 == 1 {
+        text.push_str("||");
+    }
+    text
+}
+
+/// Cut `text` down to at most `max` bytes, marking where it was cut
+fn truncate(text: &str, max: usize) -> String {
+    if text.len() <= max {
+        return text.to_owned();
+    }
+    let mut end = max - '…'.len_utf8();
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}…", &text[..end])
+}
+
+/// One line each, for as many as fit in an embed field
+fn join_lines(lines: &[String]) -> String {
+    let mut out = String::new();
+    for (idx, line) in lines.iter().enumerate() {
+        let more = format!("\n...and {} more", lines.len() - idx);
+        if out.len() + line.len() + 1 + more.len() > MAX_FIELD_LEN {
+            out.push_str(if out.is_empty() {
+                more.trim_start()
+            } else {
+                &more
+            });
+            break;
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+/// A file size for humans, like `1.5 MB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::model::id::UserId;
+
+    use super::super::tests::give;
+    use super::*;
+    use crate::discord::SourceAttachment;
+
+    fn info() -> PinInfo {
+        PinInfo {
+            medal: "🥔".to_owned(),
+            count: 5,
+            author: UserId(2),
+            guild: GuildId(1),
+            channel: ChannelId(10),
+            message: MessageId(100),
+            previous_pins: 0,
+            givers: vec![UserId(3)],
+        }
+    }
+
+    fn image(filename: &str) -> SourceAttachment {
+        SourceAttachment {
+            filename: filename.to_owned(),
+            url: format!("https://cdn.example/{}", filename),
+            size: 2048,
+            dimensions: Some((64, 64)),
+            content_type: Some("image/png".to_owned()),
+        }
+    }
+
+    fn message(content: &str, attachments: Vec<SourceAttachment>) -> SourceMessage {
+        SourceMessage {
+            id: MessageId(100),
+            channel_id: ChannelId(10),
+            guild_id: Some(GuildId(1)),
+            author_id: UserId(2),
+            author_name: "someone".to_owned(),
+            author_avatar: "https://cdn.example/avatar.png".to_owned(),
+            content: content.to_owned(),
+            attachments,
+            stickers: Vec::new(),
+            embeds: Vec::new(),
+            reply_to: None,
+        }
+    }
+
+    fn attachments_field(pin: &PinMessage) -> Option<&str> {
+        pin.embeds[0]
+            .fields
+            .iter()
+            .find(|(name, _, _)| name == "Attachments")
+            .map(|(_, value, _)| value.as_str())
+    }
+
+    #[test]
+    fn spoilers_wrap_everything_once() {
+        assert_eq!(spoiler_of("hello"), "||hello||");
+        assert_eq!(spoiler_of("a ||secret|| b"), "||a secret b||");
+        assert_eq!(spoiler_of(""), "");
+        assert_eq!(spoiler_of("||||"), "");
+    }
+
+    #[test]
+    fn cut_spoilers_get_closed() {
+        assert_eq!(close_spoilers("a ||sec".to_owned()), "a ||sec||");
+        assert_eq!(close_spoilers("a ||secret||".to_owned()), "a ||secret||");
+        assert_eq!(close_spoilers("plain".to_owned()), "plain");
+    }
+
+    #[test]
+    fn truncating_marks_the_cut_and_respects_characters() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("exactly10!", 10), "exactly10!");
+        assert_eq!(truncate("a bit too long", 10), "a bit t…");
+        // `…` is three bytes, and `é` is two, so this can't cut `é` in half
+        let cut = truncate("ééééé", 8);
+        assert!(cut.len() <= 8);
+        assert_eq!(cut, "éé…");
+    }
+
+    #[test]
+    fn spoilered_attachments_are_linked_not_shown() {
+        let template = PinTemplate::default();
+        let original = message("look", vec![image("cat.png"), image("SPOILER_dog.png")]);
+        let pin = render_pin(&template, &info(), &original, false, false);
+
+        let images = pin
+            .embeds
+            .iter()
+            .filter_map(|embed| embed.image.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(images, ["https://cdn.example/cat.png"]);
+        assert_eq!(
+            attachments_field(&pin),
+            Some("||[SPOILER_dog.png](https://cdn.example/SPOILER_dog.png)|| (2.0 KB)")
+        );
+        assert_eq!(pin.embeds[0].description, "look");
+        assert_eq!(pin.content, "🥔 5");
+    }
+
+    #[test]
+    fn spoiling_hides_everything() {
+        let template = PinTemplate::default();
+        let original = message("look ||here||", vec![image("cat.png")]);
+        let pin = render_pin(&template, &info(), &original, false, true);
+
+        assert!(pin.embeds.iter().all(|embed| embed.image.is_none()));
+        assert_eq!(pin.embeds[0].description, "||look here||");
+        assert_eq!(
+            attachments_field(&pin),
+            Some("||[cat.png](https://cdn.example/cat.png)|| (2.0 KB)")
+        );
+        // The header's still there, so people know what it is
+        assert_eq!(pin.content, "🥔 5");
+    }
+
+    #[test]
+    fn migrated_webhook_pins_are_deleted_by_the_webhook_that_sent_them() {
+        let webhook = |id, channel| PinWebhook {
+            id: WebhookId(id),
+            token: "token".to_owned(),
+            channel: ChannelId(channel),
+        };
+        let mut handler = Handler::new();
+        handler.config.threshold = 1;
+        handler.config.pin_mode = PinMode::Webhook;
+        handler.config.pin_channel = ChannelId(60);
+        handler.config.pin_webhook = Some(webhook(2, 60));
+        handler.config.old_pin_webhooks = vec![webhook(1, 50)];
+        give(&mut handler, 3, 5, 100);
+        let msg = handler.tatered_messages.get_mut(&MessageId(100)).unwrap();
+        msg.pin_id = Some(MessageId(200));
+        msg.pin_channel = Some(ChannelId(50));
+        msg.pin_webhook = Some(WebhookId(1));
+
+        let key = PinKey {
+            guild: GuildId(1),
+            channel: ChannelId(10),
+            message: MessageId(100),
+        };
+        let how = Rerender {
+            migrate: true,
+            delete_old: true,
+        };
+        match handler.plan_pin(key, Some(how)) {
+            Some(PinPlan::Move {
+                old_channel,
+                old_webhook,
+                delete_old,
+                new,
+                ..
+            }) => {
+                assert_eq!(old_channel, ChannelId(50));
+                assert_eq!(old_webhook, Some(webhook(1, 50)));
+                assert!(delete_old);
+                assert_eq!(new.pin_channel, ChannelId(60));
+                assert_eq!(new.webhook, Some(webhook(2, 60)));
+            }
+            _ => panic!("expected the pin to move"),
+        }
+
+        // Pins left where they are still get their counts updated
+        assert!(matches!(
+            handler.plan_pin(key, None),
+            Some(PinPlan::EditWebhook { webhook: sent_by, .. }) if sent_by == webhook(1, 50)
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+//! Which slash commands Discord shows, and where
+
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use anyhow::{anyhow, Context as AnyhowContext};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    builder::CreateApplicationCommands,
+    http::Http,
+    model::{
+        id::GuildId,
+        interactions::{ApplicationCommand, ApplicationCommandOptionType},
+    },
+};
+
+use super::HandlerWrapper;
+
+/// Optional sets of commands a guild can turn off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandGroup {
+    /// The leaderboards
+    Stats,
+    /// Downloading data, like `csv`
+    Exports,
+    /// Just for fun
+    Fun,
+}
+
+impl CommandGroup {
+    pub const ALL: [CommandGroup; 3] = [
+        CommandGroup::Stats,
+        CommandGroup::Exports,
+        CommandGroup::Fun,
+    ];
+
+    /// The group a slash or text command belongs to, if it's in one
+    pub fn of_command(command: &str) -> Option<Self> {
+        match command {
+            "receivers" | "givers" => Some(CommandGroup::Stats),
+            "csv" => Some(CommandGroup::Exports),
+            "random_pin" => Some(CommandGroup::Fun),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CommandGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CommandGroup::Stats => "stats",
+            CommandGroup::Exports => "exports",
+            CommandGroup::Fun => "fun",
+        })
+    }
+}
+
+impl FromStr for CommandGroup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CommandGroup::ALL
+            .iter()
+            .copied()
+            .find(|group| group.to_string() == s)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown command group `{}`, try one of: {}",
+                    s,
+                    CommandGroup::ALL
+                        .iter()
+                        .map(|group| group.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// Where slash commands get registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Registration {
+    /// Once for everywhere. It can take Discord up to an hour to show changes,
+    /// and disabled groups still show up (but refuse to run).
+    Global,
+    /// Separately in each guild, leaving out the groups it has disabled
+    PerGuild,
+    /// Only in this one guild, which shows changes right away. For development.
+    DevGuild(GuildId),
+}
+
+impl HandlerWrapper {
+    /// Use a different place to register slash commands
+    pub fn with_registration(self, registration: Registration) -> Self {
+        Self {
+            registration,
+            ..self
+        }
+    }
+
+    /// Register the commands that aren't tied to a guild.
+    /// In per-guild mode this clears out any global ones left over from before.
+    pub async fn register_global_commands(&self, http: &Http) -> Result<(), anyhow::Error> {
+        let disabled = HashSet::new();
+        match self.registration {
+            Registration::Global => {
+                ApplicationCommand::create_global_application_commands(http, |b| {
+                    configure_commands(b, &disabled)
+                })
+                .await
+                .context("registering global commands")?;
+                log::info!("Registered global commands");
+            }
+            Registration::PerGuild => {
+                ApplicationCommand::create_global_application_commands(http, |b| b)
+                    .await
+                    .context("clearing global commands")?;
+            }
+            // Leave whatever the real bot registered alone
+            Registration::DevGuild(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Register a guild's commands, if commands are registered per guild
+    pub async fn sync_guild_commands(
+        &self,
+        http: &Http,
+        guild: GuildId,
+    ) -> Result<(), anyhow::Error> {
+        match self.registration {
+            Registration::PerGuild => {}
+            Registration::DevGuild(dev_guild) if dev_guild == guild => {}
+            _ => return Ok(()),
+        }
+
+        let disabled = self
+            .guild(guild)
+            .await
+            .lock()
+            .await
+            .config
+            .disabled_command_groups
+            .clone();
+        guild
+            .create_application_commands(http, |b| configure_commands(b, &disabled))
+            .await
+            .with_context(|| format!("registering commands in guild {}", guild))?;
+        log::debug!("Registered commands in guild {}", guild);
+        Ok(())
+    }
+
+    /// Register every command everywhere it should be, all at once.
+    /// For when the bot isn't running to register them as guilds come in.
+    pub async fn register_commands(&self, http: &Http) -> Result<(), anyhow::Error> {
+        self.register_global_commands(http).await?;
+        let mut guilds = self.guild_ids().await;
+        if let Registration::DevGuild(dev_guild) = self.registration {
+            guilds.push(dev_guild);
+        }
+        for guild in guilds {
+            self.sync_guild_commands(http, guild).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Add every slash command that isn't in a disabled group
+fn configure_commands<'a>(
+    builder: &'a mut CreateApplicationCommands,
+    disabled: &HashSet<CommandGroup>,
+) -> &'a mut CreateApplicationCommands {
+    if !disabled.contains(&CommandGroup::Stats) {
+        builder
+            .create_application_command(|a| {
+                a.name("receivers")
+                    .description("Show taterboard receiver leaderboard")
+                    .create_option(|o| {
+                        o.name("page")
+                            .description("Which page of the leaderboard to show")
+                            .kind(ApplicationCommandOptionType::Integer)
+                            .required(false)
+                    })
+            })
+            .create_application_command(|a| {
+                a.name("givers")
+                    .description("Show taterboard giver leaderboard")
+                    .create_option(|o| {
+                        o.name("page")
+                            .description("Which page of the leaderboard to show")
+                            .kind(ApplicationCommandOptionType::Integer)
+                            .required(false)
+                    })
+            });
+    }
+    if !disabled.contains(&CommandGroup::Fun) {
+        builder.create_application_command(|a| {
+            a.name("random_pin")
+                .description("Dig up a random message from the taterboard")
+        });
+    }
+    builder
+}
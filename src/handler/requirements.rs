@@ -0,0 +1,316 @@
+//! Who gets to give taters that count, so alts can't pump a friend onto the board
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{RoleId, UserId};
+
+/// What someone has to meet before their taters count. Everything's off by default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GiverRequirements {
+    /// How old their account has to be, in hours
+    pub min_account_age_hours: u64,
+    /// How long they have to have been in the guild, in hours
+    pub min_member_hours: u64,
+    /// A role they have to have
+    pub role: Option<RoleId>,
+    /// A role that stops their taters counting
+    pub deny_role: Option<RoleId>,
+}
+
+/// Which bots and webhooks taters count for and from.
+/// By default they all count, like they did before there was a choice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BotPolicy {
+    /// Whether messages from bots other than us can get taters
+    pub bot_messages: bool,
+    /// What to do with messages sent through webhooks
+    pub webhook_messages: WebhookMessages,
+    /// Whether taters from bots count
+    pub bot_reactions: bool,
+}
+
+impl Default for BotPolicy {
+    fn default() -> Self {
+        Self {
+            bot_messages: true,
+            webhook_messages: WebhookMessages::default(),
+            bot_reactions: true,
+        }
+    }
+}
+
+/// Who gets the taters on a message sent through a webhook
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookMessages {
+    /// Nobody, they can't get taters
+    Ignore,
+    /// The webhook, like any other sender
+    #[default]
+    Count,
+    /// The account behind it, if PluralKit proxied it. Otherwise the webhook.
+    CreditProxies,
+}
+
+/// What a banned user's taters are kept out of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserBan {
+    Giving,
+    Receiving,
+    Both,
+}
+
+impl UserBan {
+    pub(super) fn giving(self) -> bool {
+        matches!(self, UserBan::Giving | UserBan::Both)
+    }
+
+    pub(super) fn receiving(self) -> bool {
+        matches!(self, UserBan::Receiving | UserBan::Both)
+    }
+
+    pub(super) fn describe(self) -> &'static str {
+        match self {
+            UserBan::Giving => "giving taters",
+            UserBan::Receiving => "receiving taters",
+            UserBan::Both => "giving or receiving taters",
+        }
+    }
+}
+
+/// What we know about someone reacting in a guild, from the reaction event
+#[derive(Debug, Clone)]
+pub struct ReactingMember {
+    /// When they joined, as a unix timestamp, if Discord said
+    pub joined_at: Option<i64>,
+    pub roles: Vec<RoleId>,
+}
+
+impl GiverRequirements {
+    /// Why taters from this user don't count, or None if they do.
+    /// `now` is a unix timestamp.
+    pub(super) fn rejection(
+        &self,
+        user: UserId,
+        member: Option<&ReactingMember>,
+        now: i64,
+    ) -> Option<String> {
+        let hours_since = |then: i64| (now - then).max(0) as u64 / 60 / 60;
+
+        let account_age = hours_since(user.created_at().timestamp());
+        if account_age < self.min_account_age_hours {
+            return Some(format!(
+                "their account is {} old, and needs to be {}",
+                format_hours(account_age),
+                format_hours(self.min_account_age_hours)
+            ));
+        }
+
+        let needs_member =
+            self.min_member_hours > 0 || self.role.is_some() || self.deny_role.is_some();
+        if !needs_member {
+            return None;
+        }
+        let member = match member {
+            Some(it) => it,
+            None => return Some("Discord didn't say who they are in this server".to_owned()),
+        };
+        if self.min_member_hours > 0 {
+            let member_for = member.joined_at.map(hours_since).unwrap_or(0);
+            if member_for < self.min_member_hours {
+                return Some(format!(
+                    "they joined {} ago, and need to have been here {}",
+                    format_hours(member_for),
+                    format_hours(self.min_member_hours)
+                ));
+            }
+        }
+        if let Some(role) = self.role {
+            if !member.roles.contains(&role) {
+                return Some(format!("they don't have <@&{}>", role));
+            }
+        }
+        if let Some(role) = self.deny_role {
+            if member.roles.contains(&role) {
+                return Some(format!("they have <@&{}>", role));
+            }
+        }
+        None
+    }
+
+    /// Whether anything's required at all
+    pub(super) fn any(&self) -> bool {
+        self.min_account_age_hours > 0
+            || self.min_member_hours > 0
+            || self.role.is_some()
+            || self.deny_role.is_some()
+    }
+
+    /// Each requirement, for showing admins
+    pub(super) fn describe(&self) -> String {
+        let mut lines = Vec::new();
+        if self.min_account_age_hours > 0 {
+            lines.push(format!(
+                "- Accounts have to be {} old",
+                format_hours(self.min_account_age_hours)
+            ));
+        }
+        if self.min_member_hours > 0 {
+            lines.push(format!(
+                "- Members have to have been here {}",
+                format_hours(self.min_member_hours)
+            ));
+        }
+        if let Some(role) = self.role {
+            lines.push(format!("- Givers need <@&{}>", role));
+        }
+        if let Some(role) = self.deny_role {
+            lines.push(format!(
+                "- Taters from anyone with <@&{}> don't count",
+                role
+            ));
+        }
+        if lines.is_empty() {
+            "Anyone's taters count".to_owned()
+        } else {
+            lines.join("\n")
+        }
+    }
+}
+
+/// Parse a length of time like `12h`, `3d`, or `2w` into hours. Plain numbers are hours.
+pub(super) fn parse_hours(text: &str) -> Result<u64, anyhow::Error> {
+    let (number, per) = match text.char_indices().last() {
+        Some((idx, 'h')) => (&text[..idx], 1),
+        Some((idx, 'd')) => (&text[..idx], 24),
+        Some((idx, 'w')) => (&text[..idx], 24 * 7),
+        _ => (text, 1),
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Expected a time like `12h`, `3d`, or `2w`, not `{}`", text))?;
+    Ok(number.saturating_mul(per))
+}
+
+/// `5 hours`, `3 days`, and so on
+pub(super) fn format_hours(hours: u64) -> String {
+    let (amount, unit) = if hours >= 24 && hours.is_multiple_of(24) {
+        (hours / 24, "day")
+    } else {
+        (hours, "hour")
+    };
+    if amount == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", amount, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::snowflake_at;
+    use super::*;
+
+    const HOUR: i64 = 60 * 60;
+    const NOW: i64 = 1_700_000_000;
+
+    /// A user whose account was made at the unix timestamp `secs`
+    fn user_made_at(secs: i64) -> UserId {
+        UserId(snowflake_at(secs))
+    }
+
+    fn member(joined_at: Option<i64>, roles: &[u64]) -> ReactingMember {
+        ReactingMember {
+            joined_at,
+            roles: roles.iter().copied().map(RoleId).collect(),
+        }
+    }
+
+    #[test]
+    fn hours_take_units() {
+        assert_eq!(parse_hours("12h").unwrap(), 12);
+        assert_eq!(parse_hours("3d").unwrap(), 72);
+        assert_eq!(parse_hours("2w").unwrap(), 336);
+        assert_eq!(parse_hours("5").unwrap(), 5);
+        assert_eq!(parse_hours("0").unwrap(), 0);
+        for bad in ["", "h", "d3", "3m", "-1h", "1.5d", "3 d"] {
+            assert!(parse_hours(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn hours_show_as_days_when_they_fit() {
+        assert_eq!(format_hours(1), "1 hour");
+        assert_eq!(format_hours(5), "5 hours");
+        assert_eq!(format_hours(24), "1 day");
+        assert_eq!(format_hours(36), "36 hours");
+        assert_eq!(format_hours(336), "14 days");
+    }
+
+    #[test]
+    fn nothing_required_by_default() {
+        let requirements = GiverRequirements::default();
+        assert!(!requirements.any());
+        assert_eq!(requirements.rejection(user_made_at(NOW), None, NOW), None);
+    }
+
+    #[test]
+    fn accounts_need_to_be_old_enough() {
+        let requirements = GiverRequirements {
+            min_account_age_hours: 24,
+            ..Default::default()
+        };
+        let old = user_made_at(NOW - 24 * HOUR);
+        let new = user_made_at(NOW - 24 * HOUR + 1);
+        assert_eq!(requirements.rejection(old, None, NOW), None);
+        assert!(requirements.rejection(new, None, NOW).is_some());
+        // Early IDs are from 2015
+        assert_eq!(requirements.rejection(UserId(1), None, NOW), None);
+    }
+
+    #[test]
+    fn members_need_to_have_been_here_long_enough() {
+        let requirements = GiverRequirements {
+            min_member_hours: 2,
+            ..Default::default()
+        };
+        let user = UserId(1);
+        let long_enough = member(Some(NOW - 2 * HOUR), &[]);
+        let too_new = member(Some(NOW - HOUR), &[]);
+        let unknown = member(None, &[]);
+        assert_eq!(requirements.rejection(user, Some(&long_enough), NOW), None);
+        assert!(requirements.rejection(user, Some(&too_new), NOW).is_some());
+        assert!(requirements.rejection(user, Some(&unknown), NOW).is_some());
+        assert!(requirements.rejection(user, None, NOW).is_some());
+    }
+
+    #[test]
+    fn roles_are_required_and_denied() {
+        let requirements = GiverRequirements {
+            role: Some(RoleId(7)),
+            deny_role: Some(RoleId(8)),
+            ..Default::default()
+        };
+        let user = UserId(1);
+        assert_eq!(
+            requirements.rejection(user, Some(&member(None, &[7])), NOW),
+            None
+        );
+        assert_eq!(
+            requirements
+                .rejection(user, Some(&member(None, &[])), NOW)
+                .unwrap(),
+            "they don't have <@&7>"
+        );
+        assert_eq!(
+            requirements
+                .rejection(user, Some(&member(None, &[7, 8])), NOW)
+                .unwrap(),
+            "they have <@&8>"
+        );
+        assert!(requirements.rejection(user, None, NOW).is_some());
+    }
+}
@@ -0,0 +1,268 @@
+//! How each guild wants its pins laid out
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
+
+/// Placeholders pin text can use, and what they turn into
+pub const PLACEHOLDERS: [(&str, &str); 9] = [
+    ("medal", "the potato for how many taters it has"),
+    ("count", "how many taters it has"),
+    ("author", "a mention of whoever sent it"),
+    ("channel", "a mention of the channel it's in"),
+    ("timestamp", "when it was sent"),
+    ("link", "a link to jump to it"),
+    ("ordinal", "which pin this is for its author, like `3rd`"),
+    (
+        "previous_pins",
+        "how many times its author was pinned before",
+    ),
+    ("givers", "mentions of who gave it taters"),
+];
+
+/// Placeholders that change as taters come and go, so they're only allowed in the header
+pub const LIVE_PLACEHOLDERS: [&str; 3] = ["medal", "count", "givers"];
+
+/// The first of the [`LIVE_PLACEHOLDERS`] in `text`, if it has any
+pub(super) fn live_placeholder_in(text: &str) -> Option<&'static str> {
+    LIVE_PLACEHOLDERS
+        .iter()
+        .copied()
+        .find(|name| text.contains(&format!("{{{}}}", name)))
+}
+
+/// How pins get posted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PinMode {
+    /// By the bot, with the message in an embed
+    #[default]
+    Embed,
+    /// Through a webhook, so it looks like the original author posted it again
+    Webhook,
+}
+
+/// What to do when a pin's deleted by someone other than us
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletedPins {
+    /// Forget it, and keep the message off the board like `unpin` would
+    #[default]
+    Clear,
+    /// Post it again
+    Recreate,
+}
+
+/// What to do with pins of messages from NSFW channels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NsfwPins {
+    /// Pin them like any other
+    #[default]
+    Allow,
+    /// Never pin them
+    Never,
+    /// Pin them in a board of their own. Those pins always go in embeds, since the
+    /// pin webhook only posts to the main board.
+    Board(ChannelId),
+    /// Pin them with everything hidden behind spoilers
+    Spoiler,
+}
+
+/// Don't list more givers than this, so the header can't get too long
+const MAX_GIVERS: usize = 20;
+
+/// A guild's pin layout. Text can use any of the [`PLACEHOLDERS`], like `{count}`.
+///
+/// Only the header is kept up to date as taters come and go. Everything else is
+/// filled in once, when the pin is made, so it can't use the [`LIVE_PLACEHOLDERS`].
+/// Footers are plain text, so mentions and timestamps in them show up raw.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PinTemplate {
+    /// The text above the embed
+    pub header: String,
+    /// Whether to show the sender's name and avatar
+    pub author: bool,
+    /// The field linking back to the message, if there is one
+    pub jump: Option<String>,
+    /// The small text at the bottom, if there is any
+    pub footer: Option<String>,
+}
+
+impl Default for PinTemplate {
+    fn default() -> Self {
+        Self {
+            header: "{medal} {count}".to_owned(),
+            author: true,
+            jump: Some("[**Click to jump to message!**]({link})".to_owned()),
+            footer: Some("This user has been pinned {previous_pins} times before".to_owned()),
+        }
+    }
+}
+
+/// Everything a placeholder can stand for
+#[derive(Debug, Clone)]
+pub(super) struct PinInfo {
+    pub medal: String,
+    pub count: u64,
+    pub author: UserId,
+    pub guild: GuildId,
+    pub channel: ChannelId,
+    pub message: MessageId,
+    pub previous_pins: usize,
+    pub givers: Vec<UserId>,
+}
+
+impl PinInfo {
+    /// Swap every placeholder in `text` for what it stands for.
+    /// Anything in braces that isn't a placeholder is left alone.
+    pub fn fill(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest
+                .find('}')
+                .and_then(|end| Some((self.placeholder(&rest[1..end])?, end)));
+            match value {
+                Some((value, end)) => {
+                    out.push_str(&value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    out.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn placeholder(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "medal" => self.medal.clone(),
+            "count" => self.count.to_string(),
+            "author" => format!("<@{}>", self.author),
+            "channel" => format!("<#{}>", self.channel),
+            "timestamp" => format!("<t:{}:f>", self.message.created_at().timestamp()),
+            "link" => self.message.link(self.channel, Some(self.guild)),
+            "ordinal" => ordinal(self.previous_pins + 1),
+            "previous_pins" => self.previous_pins.to_string(),
+            "givers" => {
+                let mut givers = self
+                    .givers
+                    .iter()
+                    .take(MAX_GIVERS)
+                    .map(|giver| format!("<@{}>", giver))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                if self.givers.len() > MAX_GIVERS {
+                    givers.push_str(&format!(" and {} more", self.givers.len() - MAX_GIVERS));
+                }
+                givers
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// `1st`, `2nd`, `3rd`, `4th`, ..., `11th`, ..., `21st`
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> PinInfo {
+        PinInfo {
+            medal: "🥔".to_owned(),
+            count: 5,
+            author: UserId(2),
+            guild: GuildId(1),
+            channel: ChannelId(10),
+            message: MessageId(1 << 22),
+            previous_pins: 2,
+            givers: vec![UserId(3), UserId(4)],
+        }
+    }
+
+    #[test]
+    fn every_placeholder_fills_in() {
+        let info = info();
+        let all = PLACEHOLDERS
+            .iter()
+            .map(|(name, _)| format!("{{{}}}", name))
+            .collect::<Vec<_>>()
+            .join("|");
+        assert_eq!(
+            info.fill(&all),
+            "🥔|5|<@2>|<#10>|<t:1420070400:f>|https://discord.com/channels/1/10/4194304|3rd|2|<@3>, <@4>"
+        );
+    }
+
+    #[test]
+    fn other_braces_are_left_alone() {
+        let info = info();
+        assert_eq!(info.fill("{nope} {count}"), "{nope} 5");
+        assert_eq!(info.fill("{{count}}"), "{5}");
+        assert_eq!(info.fill("{count"), "{count");
+        assert_eq!(info.fill("count} {"), "count} {");
+        assert_eq!(info.fill("{}"), "{}");
+        assert_eq!(info.fill("no placeholders"), "no placeholders");
+    }
+
+    #[test]
+    fn long_giver_lists_are_cut_short() {
+        let mut info = info();
+        info.givers = (100..100 + MAX_GIVERS as u64 + 3).map(UserId).collect();
+        let givers = info.fill("{givers}");
+        assert_eq!(givers.matches("<@").count(), MAX_GIVERS);
+        assert!(givers.ends_with(" and 3 more"));
+    }
+
+    #[test]
+    fn ordinals_get_the_right_suffix() {
+        let cases = [
+            (1, "1st"),
+            (2, "2nd"),
+            (3, "3rd"),
+            (4, "4th"),
+            (11, "11th"),
+            (12, "12th"),
+            (13, "13th"),
+            (21, "21st"),
+            (112, "112th"),
+            (122, "122nd"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(ordinal(n), expected);
+        }
+    }
+
+    #[test]
+    fn live_placeholders_are_spotted() {
+        assert_eq!(live_placeholder_in("{author} got {count}"), Some("count"));
+        assert_eq!(live_placeholder_in("thanks {givers}"), Some("givers"));
+        assert_eq!(live_placeholder_in("{author} in {channel}"), None);
+        assert_eq!(live_placeholder_in("count medal"), None);
+        assert_eq!(
+            live_placeholder_in(&PinTemplate::default().footer.unwrap()),
+            None
+        );
+        assert_eq!(
+            live_placeholder_in(&PinTemplate::default().jump.unwrap()),
+            None
+        );
+    }
+}
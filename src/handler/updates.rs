@@ -1,124 +1,208 @@
-use std::time::{Duration, Instant};
+use std::{future::Future, time::Duration};
 
-use serenity::{client::Context, model::prelude::Activity};
+use rand::Rng;
+use serenity::{
+    client::Context,
+    model::{id::GuildId, prelude::Activity},
+};
 
 use super::HandlerWrapper;
 
+/// How often the periodic jobs run
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    /// How often to save everything
+    pub save_every: Duration,
+    /// How often to change status
+    pub status_every: Duration,
+    /// Each wait is stretched or shrunk at random by up to this fraction of itself,
+    /// so jobs don't all line up
+    pub jitter: f64,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            // Save every half hour
+            save_every: Duration::from_secs(60 * 30),
+            // Update status every hour
+            status_every: Duration::from_secs(60 * 60),
+            jitter: 0.1,
+        }
+    }
+}
+
 /// Handles periodic updates and saving
 pub struct Updates {
-    /// Time since last save
-    last_save: Instant,
-    /// Time since last status change
-    last_status_change: Instant,
-    /// Index of the status message function.
-    /// Is None if there hasn't been a status message yet.
-    status_idx: Option<usize>,
+    schedule: Schedule,
+    /// Whether the jobs have been started.
+    /// `ready` can happen more than once, but we only want one of each job.
+    started: bool,
+    /// Index of the next status message function
+    status_idx: usize,
 }
 
 impl Updates {
     /// Make a new one!
-    pub fn new() -> Self {
+    pub fn new(schedule: Schedule) -> Self {
         Self {
-            last_save: Instant::now(),
-            last_status_change: Instant::now(),
-            status_idx: None,
+            schedule,
+            started: false,
+            status_idx: 0,
         }
     }
-
-    /// Save every half hour
-    const SAVE_EVERY: Duration = Duration::from_secs(60 * 30);
-    /// Update status every hour
-    const UPDATE_EVERY: Duration = Duration::from_secs(60 * 60);
 }
 
 impl HandlerWrapper {
-    /// Check if anything needs to be updated
-    pub async fn check_updates(&self, ctx: &Context) -> Result<(), anyhow::Error> {
+    /// Start the periodic jobs in the background, if they aren't running already
+    pub async fn start_updates(&self, ctx: Context) {
         let mut updates = self.updates.lock().await;
-        let now = Instant::now();
-
-        if now.duration_since(updates.last_save) >= Updates::SAVE_EVERY {
-            // gotta save!
-            log::debug!("saving at {:?}", &now);
-            updates.last_save = now;
-            let lock = self.handlers.lock().await;
-            HandlerWrapper::save_all(&self.save_dir_path, &*lock).await?;
+        if updates.started {
+            return;
         }
-        if now.duration_since(updates.last_status_change) >= Updates::UPDATE_EVERY
-            || updates.status_idx.is_none()
-        {
-            log::debug!("updating status at {:?}", &now);
-            updates.last_status_change = now;
-            let idx = updates.status_idx.unwrap_or(0);
-
-            // number of status messages there are
-            const STATUSES_COUNT: usize = 4;
-
-            let activity = match idx {
-                0 => {
-                    // Get number of potatoes awarded everywhere
-                    let handlers = self.handlers.lock().await;
-                    let potatoes: u64 = handlers
-                        .iter()
-                        .map(|(_, handler)| {
-                            handler
-                                .taters_given
-                                .iter()
-                                .map(|(_, &count)| count)
-                                .sum::<u64>()
-                        })
-                        .sum();
-                    Activity::playing(format!("with the {} potatoes given", potatoes).as_str())
-                }
-                1 => {
-                    // Get number of servers its in
-                    let handlers = self.handlers.lock().await;
-                    Activity::playing(format!("in {} servers", handlers.len()).as_str())
-                }
-                2 => {
-                    // Get number of messages listened to
-                    let handlers = self.handlers.lock().await;
-                    let messages: u64 = handlers
-                        .iter()
-                        .map(|(_, handler)| handler.tatered_messages.len() as u64)
-                        .sum();
-                    Activity::listening(format!("to {} potatoed messages", messages).as_str())
-                }
-                3 => {
-                    // Get maximum potato count
-                    let handlers = self.handlers.lock().await;
-                    let max: u64 = handlers
-                        .iter()
-                        .map(|(_, handler)| {
-                            handler
-                                .tatered_messages
-                                .iter()
-                                .map(|(_id, msg)| msg.count)
-                                .max()
-                                .unwrap_or(0)
-                        })
-                        .max()
-                        .unwrap_or(0);
-                    Activity::competing(
-                        format!("the record {} potatoes on one message", max).as_str(),
-                    )
-                }
-                oh_no => {
-                    // oh no
-                    Activity::playing(
-                        format!("someone file an issue on Github, the index is {} when it should be less than {}", oh_no, STATUSES_COUNT
-                    ).as_str())
+        updates.started = true;
+        let schedule = updates.schedule.clone();
+
+        let this = self.clone();
+        spawn_periodic(
+            "save",
+            schedule.save_every,
+            schedule.jitter,
+            false,
+            move || {
+                let this = this.clone();
+                async move { this.save_all().await }
+            },
+        );
+        let this = self.clone();
+        spawn_periodic(
+            "status",
+            schedule.status_every,
+            schedule.jitter,
+            true,
+            move || {
+                let this = this.clone();
+                let ctx = ctx.clone();
+                async move {
+                    this.rotate_status(&ctx).await;
+                    Ok(())
                 }
-            };
-            ctx.set_activity(activity).await;
+            },
+        );
+    }
+
+    /// Move on to the next status message
+    async fn rotate_status(&self, ctx: &Context) {
+        let idx = {
+            let mut updates = self.updates.lock().await;
+            let idx = updates.status_idx;
+            updates.status_idx = (idx + 1) % STATUSES_COUNT;
+            idx
+        };
+        log::debug!("updating status to #{}", idx);
+
+        let stats = self.guild_stats().await;
+        let activity = match idx {
+            0 => {
+                // Get number of potatoes awarded everywhere
+                let potatoes = stats.iter().map(|it| it.taters_given).sum::<u64>();
+                Activity::playing(format!("with the {} potatoes given", potatoes).as_str())
+            }
+            1 => {
+                // Get number of servers its in
+                Activity::playing(format!("in {} servers", stats.len()).as_str())
+            }
+            2 => {
+                // Get number of messages listened to
+                let messages = stats.iter().map(|it| it.tatered_messages).sum::<u64>();
+                Activity::listening(format!("to {} potatoed messages", messages).as_str())
+            }
+            3 => {
+                // Get maximum potato count
+                let max = stats.iter().map(|it| it.max_taters).max().unwrap_or(0);
+                Activity::competing(format!("the record {} potatoes on one message", max).as_str())
+            }
+            oh_no => {
+                // oh no
+                Activity::playing(
+                    format!("someone file an issue on Github, the index is {} when it should be less than {}", oh_no, STATUSES_COUNT
+                ).as_str())
+            }
+        };
+        ctx.set_activity(activity).await;
+    }
+
+    /// The numbers behind the status messages, for each guild
+    pub async fn guild_stats(&self) -> Vec<GuildStats> {
+        let mut stats = Vec::new();
+        for (guild, state) in self.all_guilds().await {
+            let this = state.lock().await;
+            stats.push(GuildStats {
+                guild,
+                taters_given: this.taters_given.values().sum(),
+                tatered_messages: this.tatered_messages.len() as u64,
+                max_taters: this
+                    .tatered_messages
+                    .values()
+                    .map(|msg| msg.count)
+                    .max()
+                    .unwrap_or(0),
+            });
+        }
+        stats
+    }
+}
+
+/// How busy a guild's been
+#[derive(Debug, Clone)]
+pub struct GuildStats {
+    pub guild: GuildId,
+    /// Potatoes given out, all told
+    pub taters_given: u64,
+    /// Messages with any potatoes on them
+    pub tatered_messages: u64,
+    /// The most potatoes on any one message
+    pub max_taters: u64,
+}
 
-            let mut idx = idx + 1;
-            if idx >= STATUSES_COUNT {
-                idx = 0;
+/// number of status messages there are
+const STATUSES_COUNT: usize = 4;
+
+/// Never wait less than this between runs of a periodic job, whatever it's set to
+const MIN_PERIOD: Duration = Duration::from_secs(60);
+
+/// Run `job` forever in the background, waiting about `every` between runs, and never
+/// less than [`MIN_PERIOD`]. If `right_away` is set, it runs once before the first wait.
+fn spawn_periodic<F, Fut>(
+    name: &'static str,
+    every: Duration,
+    jitter: f64,
+    right_away: bool,
+    job: F,
+) where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), anyhow::Error>> + Send,
+{
+    tokio::spawn(async move {
+        if !right_away {
+            tokio::time::sleep(jittered(every, jitter).max(MIN_PERIOD)).await;
+        }
+        loop {
+            log::debug!("running periodic job `{}`", name);
+            if let Err(oh_no) = job().await {
+                log::error!("periodic job `{}`: {:?}", name, oh_no);
             }
-            updates.status_idx = Some(idx);
+            tokio::time::sleep(jittered(every, jitter).max(MIN_PERIOD)).await;
         }
+    });
+}
 
-        Ok(())
+/// Stretch or shrink `every` by a random amount up to `jitter` of itself
+fn jittered(every: Duration, jitter: f64) -> Duration {
+    let jitter = jitter.clamp(0.0, 1.0);
+    if jitter == 0.0 {
+        return every;
     }
+    let factor = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+    every.mul_f64(factor)
 }
@@ -0,0 +1,299 @@
+//! Spotting people who mostly tater each other, going by who gave taters on whose messages
+
+use std::collections::{HashMap, HashSet};
+
+use serenity::model::{id::UserId, misc::Mentionable};
+
+use super::{Handler, TateredMessage};
+
+/// Ignore anyone who's given fewer taters than this, there's not enough to go on
+const MIN_GIVEN: u64 = 5;
+/// Flag givers who send at least this share of their taters to one person
+const FOCUSED_SHARE: f64 = 0.5;
+/// Flag pairs who've each given the other at least this many taters
+const MIN_RECIPROCAL: u64 = 3;
+/// Flag groups that keep at least this share of their taters to themselves
+const CLOSED_SHARE: f64 = 0.75;
+
+/// How many taters each giver has given each receiver, keyed by `(giver, receiver)`.
+/// Taters from saves older than `givers` aren't in here, since we don't know who gave them.
+pub(super) fn count_pairs<'a>(
+    messages: impl IntoIterator<Item = &'a TateredMessage>,
+) -> HashMap<(UserId, UserId), u64> {
+    let mut pairs = HashMap::new();
+    for msg in messages {
+        for giver in &msg.givers {
+            *pairs.entry((*giver, msg.sender)).or_insert(0) += 1;
+        }
+    }
+    pairs
+}
+
+/// Something that looks off, and how much
+struct Finding {
+    score: f64,
+    evidence: String,
+}
+
+impl Handler {
+    /// Redo `taters_between` from scratch, after messages were moved around in bulk
+    pub(super) fn recount_pairs(&mut self) {
+        self.taters_between = count_pairs(self.tatered_messages.values());
+    }
+
+    /// How many taters from `giver` to `receiver` we know about
+    pub(super) fn pair_count(&self, giver: UserId, receiver: UserId) -> u64 {
+        self.taters_between
+            .get(&(giver, receiver))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Why another tater from `giver` to `receiver` wouldn't count, if it'd go over the pair cap
+    pub(super) fn over_pair_cap(&self, giver: UserId, receiver: UserId) -> Option<String> {
+        let cap = self.config.pair_cap?;
+        let given = self.pair_count(giver, receiver);
+        if giver == receiver || given < cap {
+            return None;
+        }
+        Some(format!(
+            "they've already given {} {} taters, the most that count",
+            receiver.mention(),
+            given
+        ))
+    }
+
+    /// Anyone whose taters mostly go to one person, pairs who tater each other a lot,
+    /// and groups that only tater each other, most suspicious first
+    pub(super) fn vote_ring_report(&self) -> Vec<String> {
+        // Only count what we know the giver of, so the shares add up
+        let mut given = HashMap::<UserId, u64>::new();
+        for (&(giver, _), &count) in &self.taters_between {
+            *given.entry(giver).or_insert(0) += count;
+        }
+        let share = |giver: UserId, count: u64| count as f64 / given[&giver] as f64;
+
+        let mut findings = Vec::new();
+        for (&(giver, receiver), &count) in &self.taters_between {
+            if given[&giver] < MIN_GIVEN || share(giver, count) < FOCUSED_SHARE {
+                continue;
+            }
+            findings.push(Finding {
+                score: share(giver, count) * count as f64,
+                evidence: format!(
+                    "{} gave {} of their {} taters ({:.0}%) to {}",
+                    giver.mention(),
+                    count,
+                    given[&giver],
+                    share(giver, count) * 100.0,
+                    receiver.mention()
+                ),
+            });
+        }
+
+        for (&(a, b), &there) in &self.taters_between {
+            // Each pair once
+            if a >= b {
+                continue;
+            }
+            let back = self.pair_count(b, a);
+            if there.min(back) < MIN_RECIPROCAL {
+                continue;
+            }
+            findings.push(Finding {
+                score: (share(a, there) + share(b, back)) * there.min(back) as f64,
+                evidence: format!(
+                    "{} and {} tater each other: {} one way ({:.0}% of what they gave), {} the other ({:.0}%)",
+                    a.mention(),
+                    b.mention(),
+                    there,
+                    share(a, there) * 100.0,
+                    back,
+                    share(b, back) * 100.0
+                ),
+            });
+        }
+
+        for group in self.close_groups(&given) {
+            let total = group.iter().map(|user| given[user]).sum::<u64>();
+            let inside = group
+                .iter()
+                .flat_map(|&giver| group.iter().map(move |&receiver| (giver, receiver)))
+                .map(|(giver, receiver)| self.pair_count(giver, receiver))
+                .sum::<u64>();
+            let kept = inside as f64 / total as f64;
+            if kept < CLOSED_SHARE {
+                continue;
+            }
+            let members = group
+                .iter()
+                .map(|user| user.mention().to_string())
+                .collect::<Vec<_>>();
+            findings.push(Finding {
+                score: kept * inside as f64,
+                evidence: format!(
+                    "{} gave {} of their {} taters ({:.0}%) to each other",
+                    members.join(", "),
+                    inside,
+                    total,
+                    kept * 100.0
+                ),
+            });
+        }
+
+        findings.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.evidence.cmp(&b.evidence))
+        });
+        findings.into_iter().map(|found| found.evidence).collect()
+    }
+
+    /// Groups of three or more people joined by pairs who tater each other a lot,
+    /// among people who've given enough to judge.
+    /// A group that's most of the server is just the server, so those are left out.
+    fn close_groups(&self, given: &HashMap<UserId, u64>) -> Vec<Vec<UserId>> {
+        let judged = given.values().filter(|&&count| count >= MIN_GIVEN).count();
+        let mut links = HashMap::<UserId, Vec<UserId>>::new();
+        for (&(a, b), &there) in &self.taters_between {
+            if a < b
+                && given[&a] >= MIN_GIVEN
+                && given.get(&b).copied().unwrap_or(0) >= MIN_GIVEN
+                && there.min(self.pair_count(b, a)) >= MIN_RECIPROCAL
+            {
+                links.entry(a).or_default().push(b);
+                links.entry(b).or_default().push(a);
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let mut groups = Vec::new();
+        for &start in links.keys() {
+            if !seen.insert(start) {
+                continue;
+            }
+            let mut group = vec![start];
+            let mut idx = 0;
+            while idx < group.len() {
+                for &next in &links[&group[idx]] {
+                    if seen.insert(next) {
+                        group.push(next);
+                    }
+                }
+                idx += 1;
+            }
+            if group.len() >= 3 && group.len() * 2 <= judged {
+                group.sort();
+                groups.push(group);
+            }
+        }
+        groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::give;
+    use super::*;
+
+    /// Give `count` taters from `giver` to `receiver`, each on a new message
+    fn give_many(handler: &mut Handler, giver: u64, receiver: u64, count: u64) {
+        for _ in 0..count {
+            let message = handler.tatered_messages.len() as u64 + 1000;
+            assert!(give(handler, giver, receiver, message));
+        }
+    }
+
+    fn flags(report: &[String], who: &[u64]) -> bool {
+        report.iter().any(|line| {
+            who.iter()
+                .all(|user| line.contains(&UserId(*user).mention().to_string()))
+        })
+    }
+
+    #[test]
+    fn pair_cap_stops_at_the_cap() {
+        let mut handler = Handler::new();
+        give_many(&mut handler, 2, 3, 2);
+        assert_eq!(handler.over_pair_cap(UserId(2), UserId(3)), None);
+
+        handler.config.pair_cap = Some(3);
+        assert_eq!(handler.over_pair_cap(UserId(2), UserId(3)), None);
+        give_many(&mut handler, 2, 3, 1);
+        assert!(handler.over_pair_cap(UserId(2), UserId(3)).is_some());
+        // Only that way round
+        assert_eq!(handler.over_pair_cap(UserId(3), UserId(2)), None);
+        // Self-taters are turned away elsewhere, they don't use up the cap
+        handler.taters_between.insert((UserId(4), UserId(4)), 10);
+        assert_eq!(handler.over_pair_cap(UserId(4), UserId(4)), None);
+    }
+
+    #[test]
+    fn focused_givers_are_flagged_once_theres_enough_to_judge() {
+        let mut handler = Handler::new();
+        give_many(&mut handler, 2, 10, 4);
+        assert!(handler.vote_ring_report().is_empty());
+
+        give_many(&mut handler, 2, 10, 1);
+        give_many(&mut handler, 3, 11, 1);
+        let report = handler.vote_ring_report();
+        assert_eq!(report.len(), 1, "{:?}", report);
+        assert!(flags(&report, &[2, 10]));
+    }
+
+    #[test]
+    fn spread_out_givers_arent_flagged() {
+        let mut handler = Handler::new();
+        for receiver in 10..20 {
+            give_many(&mut handler, 2, receiver, 1);
+        }
+        assert!(handler.vote_ring_report().is_empty());
+    }
+
+    #[test]
+    fn reciprocal_pairs_are_flagged() {
+        let mut handler = Handler::new();
+        give_many(&mut handler, 2, 3, 3);
+        give_many(&mut handler, 3, 2, 3);
+        // Spread out enough that neither is focused on the other
+        for receiver in 10..16 {
+            give_many(&mut handler, 2, receiver, 1);
+            give_many(&mut handler, 3, receiver, 1);
+        }
+        let report = handler.vote_ring_report();
+        assert_eq!(report.len(), 1, "{:?}", report);
+        assert!(flags(&report, &[2, 3]));
+    }
+
+    #[test]
+    fn closed_groups_are_flagged_unless_theyre_most_of_the_server() {
+        let mut handler = Handler::new();
+        let group = [2, 3, 4];
+        for giver in group {
+            for receiver in group {
+                if giver != receiver {
+                    give_many(&mut handler, giver, receiver, 3);
+                }
+            }
+        }
+        let groups = |handler: &Handler| {
+            handler
+                .vote_ring_report()
+                .into_iter()
+                .filter(|line| line.contains("to each other"))
+                .collect::<Vec<_>>()
+        };
+        // Nobody else has given enough to judge, so that's everyone
+        assert!(groups(&handler).is_empty());
+
+        for giver in 20..23 {
+            for receiver in 30..35 {
+                give_many(&mut handler, giver, receiver, 1);
+            }
+        }
+        let found = groups(&handler);
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(flags(&found, &group));
+        assert!(found[0].contains("18 of their 18 taters (100%)"));
+    }
+}
@@ -0,0 +1,8 @@
+//! The taterboard bot, shared between the bot binary and its tooling
+
+pub mod bot_config;
+pub mod discord;
+pub mod handler;
+pub mod metrics;
+pub mod server;
+pub mod storage;
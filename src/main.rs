@@ -1,28 +1,318 @@
-mod handler;
+use anyhow::{anyhow, Context};
+use clap::{Args, Parser, Subcommand};
+use std::{env, error::Error, fs, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use anyhow::Context;
-use std::{env, error::Error, path::PathBuf};
+use serenity::model::id::{GuildId, MessageId, UserId};
+use serenity::{client::bridge::gateway::GatewayIntents, http::Http, Client};
+use taterboard::{
+    bot_config::{BotConfig, CommandScope},
+    discord::SerenityDiscord,
+    handler::{validate_data, HandlerWrapper, Registration},
+    server,
+    storage::{Storage, StorageKind},
+};
 
-use handler::HandlerWrapper;
-use serenity::model::interactions::{ApplicationCommand, ApplicationCommandOptionType};
-use serenity::{client::bridge::gateway::GatewayIntents, Client};
+/// How long each step of shutting down gets before we give up on it, unless configured otherwise
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A bot used to save messages blessed with tiny potatoes
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Bot config file. Flags and environment variables override what's in it.
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Directory guild data is saved in
+    #[arg(long, global = true, env = "TATERBOARD_DATA_DIR", value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+    /// Log filter, like `info` or `taterboard=debug`. Defaults to `RUST_LOG`.
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    /// Where guild data is kept [default: json]
+    #[arg(long, global = true, value_enum)]
+    storage: Option<StorageKind>,
+    /// Application ID of the bot
+    #[arg(long, global = true, env = "APP_ID")]
+    app_id: Option<u64>,
+    /// Where to register slash commands [default: global]
+    #[arg(long, global = true, value_enum)]
+    commands: Option<CommandScope>,
+    /// Register slash commands to just this guild, where they show up right away, instead of globally
+    #[arg(
+        long,
+        global = true,
+        env = "TATERBOARD_DEV_GUILD",
+        value_name = "GUILD_ID"
+    )]
+    dev_guild: Option<u64>,
+    /// Serve Prometheus metrics at http://<ADDR>/metrics, like `127.0.0.1:9184`
+    #[arg(
+        long,
+        global = true,
+        env = "TATERBOARD_METRICS_ADDR",
+        value_name = "ADDR"
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[command(flatten)]
+    schedule: ScheduleArgs,
+    /// The data directory, the way it was passed before there were flags
+    #[arg(hide = true, value_name = "DATA_DIR")]
+    legacy_data_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Schedule")]
+struct ScheduleArgs {
+    /// Minutes between saves [default: 30]
+    #[arg(long, global = true, env = "TATERBOARD_SAVE_MINUTES")]
+    save_minutes: Option<u64>,
+    /// Minutes between status changes [default: 60]
+    #[arg(long, global = true, env = "TATERBOARD_STATUS_MINUTES")]
+    status_minutes: Option<u64>,
+    /// Fraction each wait is randomly nudged by [default: 0.1]
+    #[arg(long, global = true, env = "TATERBOARD_JITTER")]
+    jitter: Option<f64>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Connect to Discord and run the bot. This is the default.
+    Run,
+    /// Register the slash commands with Discord, then exit.
+    /// Saved data is only read when commands are registered per guild.
+    RegisterCommands,
+    /// Print saved data as json
+    Export {
+        /// Only export this guild
+        #[arg(long, value_name = "GUILD_ID")]
+        guild: Option<u64>,
+        /// Write to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Check that saved data loads and adds up
+    ValidateData,
+    /// Look at or change saved data directly. Stop the bot first, or it'll save over your changes.
+    #[command(subcommand)]
+    Data(DataCommand),
+}
+
+#[derive(Subcommand)]
+enum DataCommand {
+    /// List every guild with saved data
+    Guilds,
+    /// Print a guild's config
+    ShowConfig {
+        #[arg(long, value_name = "GUILD_ID")]
+        guild: u64,
+    },
+    /// Print a page of a guild's leaderboard
+    Leaderboard {
+        #[arg(long, value_name = "GUILD_ID")]
+        guild: u64,
+        /// `receivers` or `givers`
+        #[arg(long, default_value = "receivers")]
+        board: String,
+        #[arg(long, default_value_t = 1)]
+        page: usize,
+    },
+    /// Set one field of a guild's config. The value is json, or a plain string.
+    SetConfig {
+        #[arg(long, value_name = "GUILD_ID")]
+        guild: u64,
+        field: String,
+        value: String,
+    },
+    /// Run an admin command on a guild, like `set_threshold 3`
+    ConfigCommand {
+        #[arg(long, value_name = "GUILD_ID")]
+        guild: u64,
+        #[arg(required = true, num_args = 1..)]
+        command: Vec<String>,
+    },
+    /// Fold one guild's taters into another's, then delete the first
+    Merge {
+        #[arg(long, value_name = "GUILD_ID")]
+        from: u64,
+        #[arg(long, value_name = "GUILD_ID")]
+        into: u64,
+    },
+    /// Move some messages, and the taters received for them, into another guild
+    Split {
+        #[arg(long, value_name = "GUILD_ID")]
+        from: u64,
+        #[arg(long, value_name = "GUILD_ID")]
+        into: u64,
+        #[arg(required = true, value_name = "MESSAGE_ID")]
+        messages: Vec<u64>,
+    },
+    /// Reset counts that went below zero back to zero
+    FixCounts {
+        /// Only fix this guild
+        #[arg(long, value_name = "GUILD_ID")]
+        guild: Option<u64>,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init_from_env(env_logger::Env::default());
-    log::info!("taterboard v{} initializing", env!("CARGO_PKG_VERSION"));
+    let cli = Cli::parse();
+    let config = match &cli.config {
+        Some(path) => BotConfig::load(path)?,
+        None => BotConfig::default(),
+    };
+    let legacy_data_dir = cli.legacy_data_dir.is_some();
+    let config = BotConfig {
+        data_dir: cli.data_dir.or(cli.legacy_data_dir).or(config.data_dir),
+        log_level: cli.log_level.or(config.log_level),
+        storage: cli.storage.or(config.storage),
+        app_id: cli.app_id.or(config.app_id),
+        commands: cli.commands.or(config.commands),
+        dev_guild: cli.dev_guild.or(config.dev_guild),
+        metrics_addr: cli.metrics_addr.or(config.metrics_addr),
+        save_minutes: cli.schedule.save_minutes.or(config.save_minutes),
+        status_minutes: cli.schedule.status_minutes.or(config.status_minutes),
+        jitter: cli.schedule.jitter.or(config.jitter),
+        token: env::var("TATERBOARD_TOKEN").ok().or(config.token),
+        ..config
+    };
+    // Catch bad times now, rather than once it's connected
+    config.schedule()?;
+
+    match &config.log_level {
+        Some(filter) => env_logger::Builder::new().parse_filters(filter).init(),
+        None => env_logger::init_from_env(env_logger::Env::default()),
+    }
+    if legacy_data_dir {
+        log::warn!(
+            "Passing the data directory on its own is deprecated, use `taterboard --data-dir <DIR>`"
+        );
+    }
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(config).await?,
+        Command::RegisterCommands => {
+            let http = Http::new_with_token_application_id(&token(&config)?, app_id(&config)?);
+            let registration = config.registration();
+            // Only per-guild registration cares which guilds we know about
+            let storage = match registration {
+                Registration::Global => StorageKind::Memory.open(None)?,
+                _ => open_storage(&config)?,
+            };
+            let wrapper = HandlerWrapper::new(storage)?.with_registration(registration);
+            wrapper.register_commands(&http).await?;
+        }
+        Command::Export { guild, output } => {
+            let wrapper = HandlerWrapper::new(open_storage(&config)?)?;
+            let json = wrapper.export(guild.map(GuildId)).await?;
+            let json = serde_json::to_string_pretty(&json)?;
+            match output {
+                Some(path) => {
+                    fs::write(&path, json).with_context(|| format!("writing {:?}", path))?
+                }
+                None => println!("{}", json),
+            }
+        }
+        Command::ValidateData => {
+            let problems = validate_data(&*open_storage(&config)?)?;
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                return Err(anyhow!("Found {} problems", problems.len()).into());
+            }
+            println!("Everything looks good!");
+        }
+        Command::Data(command) => run_data_command(&config, command).await?,
+    }
+    Ok(())
+}
 
-    let token = env::var("TATERBOARD_TOKEN").context("Missing bot token")?;
-    let app_id = env::var("APP_ID").context("Missing app id")?;
-    let app_id = app_id.parse::<u64>().context("Malformed app id")?;
-    let path_to_save = env::args()
-        .nth(1)
-        .expect("must provide path to directory to save json");
-    let path_to_save = PathBuf::from(path_to_save);
+/// Work on the saved data directly, saving it again if anything changed
+async fn run_data_command(config: &BotConfig, command: DataCommand) -> Result<(), anyhow::Error> {
+    let wrapper = HandlerWrapper::new(open_storage(config)?)?;
+    match command {
+        DataCommand::Guilds => {
+            for stats in wrapper.guild_stats().await {
+                println!(
+                    "{}: {} tatered messages, {} taters given, record {}",
+                    stats.guild, stats.tatered_messages, stats.taters_given, stats.max_taters
+                );
+            }
+            return Ok(());
+        }
+        DataCommand::ShowConfig { guild } => {
+            let json = wrapper.config_json(GuildId(guild)).await?;
+            println!("{}", serde_json::to_string_pretty(&json)?);
+            return Ok(());
+        }
+        DataCommand::Leaderboard { guild, board, page } => {
+            let (title, body, footer) = wrapper
+                .leaderboard(GuildId(guild), &board, page, UserId(0))
+                .await?;
+            println!("{}\n{}{}", title, body, footer);
+            return Ok(());
+        }
+        DataCommand::SetConfig {
+            guild,
+            field,
+            value,
+        } => {
+            let value = serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value));
+            wrapper
+                .set_config_field(GuildId(guild), &field, value)
+                .await?;
+            println!("Set `{}`", field);
+        }
+        DataCommand::ConfigCommand { guild, command } => {
+            let reply = wrapper
+                .run_config_command(GuildId(guild), &command.join(" "))
+                .await?;
+            println!("{}", reply);
+        }
+        DataCommand::Merge { from, into } => {
+            wrapper.merge_guilds(GuildId(from), GuildId(into)).await?;
+            println!("Merged guild {} into {}", from, into);
+        }
+        DataCommand::Split {
+            from,
+            into,
+            messages,
+        } => {
+            let messages = messages.into_iter().map(MessageId).collect::<Vec<_>>();
+            let moved = wrapper
+                .split_guild(GuildId(from), GuildId(into), &messages)
+                .await?;
+            println!("Moved {} messages from guild {} to {}", moved, from, into);
+        }
+        DataCommand::FixCounts { guild } => {
+            let fixes = wrapper.fix_counts(guild.map(GuildId)).await?;
+            for fix in &fixes {
+                println!("{}", fix);
+            }
+            println!("Fixed {} counts", fixes.len());
+        }
+    }
+    wrapper.save_all().await
+}
+
+/// Connect to Discord and run until we're told to stop
+async fn run(config: BotConfig) -> Result<(), anyhow::Error> {
+    log::info!("taterboard v{} initializing", env!("CARGO_PKG_VERSION"));
 
-    log::debug!("Saving data to {:?}", path_to_save);
-    tokio::fs::create_dir_all(&path_to_save).await?;
+    let token = token(&config)?;
+    let app_id = app_id(&config)?;
+    let shutdown_timeout = config
+        .shutdown_timeout_secs
+        .map(Duration::from_secs)
+        .unwrap_or(SHUTDOWN_TIMEOUT);
 
+    let wrapper = HandlerWrapper::new(open_storage(&config)?)?
+        .with_schedule(config.schedule()?)
+        .with_registration(config.registration());
     let mut client = Client::builder(&token)
         .intents(
             GatewayIntents::GUILDS
@@ -30,42 +320,99 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 | GatewayIntents::GUILD_MESSAGES
                 | GatewayIntents::GUILD_MESSAGE_REACTIONS,
         )
-        .event_handler(HandlerWrapper::new(path_to_save)?)
+        .event_handler(wrapper.clone())
         .application_id(app_id)
         .await?;
 
-    ApplicationCommand::create_global_application_commands(
-        &client.cache_and_http.http,
-        configure_commands,
-    )
-    .await?;
+    if let Some(addr) = config.metrics_addr {
+        let wrapper = wrapper.clone();
+        tokio::spawn(async move {
+            if let Err(oh_no) = server::serve(addr, wrapper).await {
+                log::error!("Metrics server: {:?}", oh_no);
+            }
+        });
+    }
+
+    // Guild commands are registered as each guild comes in
+    wrapper
+        .register_global_commands(&client.cache_and_http.http)
+        .await?;
+
+    let shard_manager = Arc::clone(&client.shard_manager);
+    let discord = SerenityDiscord::from_cache_and_http(&client.cache_and_http);
+    tokio::select! {
+        res = client.start() => res?,
+        res = shutdown_signal() => {
+            res?;
+            log::info!("Shutting down");
+            wrapper.stop_accepting_events();
+
+            if tokio::time::timeout(shutdown_timeout, wrapper.flush_pins(&discord))
+                .await
+                .is_err()
+            {
+                log::warn!("Ran out of time updating pins, the rest will be done on the next start");
+            }
+            let unsaved = wrapper.save_all_within(shutdown_timeout).await?;
+            if unsaved.is_empty() {
+                log::info!("Saved everything");
+            } else {
+                log::error!(
+                    "Ran out of time saving, these guilds weren't saved: {}",
+                    unsaved
+                        .iter()
+                        .map(|guild| guild.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
 
-    client.start().await?;
+            if tokio::time::timeout(shutdown_timeout, async {
+                shard_manager.lock().await.shutdown_all().await
+            })
+            .await
+            .is_err()
+            {
+                log::warn!("Ran out of time shutting down shards");
+            }
+        }
+    }
     Ok(())
 }
 
-fn configure_commands(
-    builder: &mut serenity::builder::CreateApplicationCommands,
-) -> &mut serenity::builder::CreateApplicationCommands {
-    builder
-        .create_application_command(|a| {
-            a.name("receivers")
-                .description("Show taterboard receiver leaderboard")
-                .create_option(|o| {
-                    o.name("page")
-                        .description("Which page of the leaderboard to show")
-                        .kind(ApplicationCommandOptionType::Integer)
-                        .required(false)
-                })
-        })
-        .create_application_command(|a| {
-            a.name("givers")
-                .description("Show taterboard giver leaderboard")
-                .create_option(|o| {
-                    o.name("page")
-                        .description("Which page of the leaderboard to show")
-                        .kind(ApplicationCommandOptionType::Integer)
-                        .required(false)
-                })
-        })
+fn token(config: &BotConfig) -> Result<String, anyhow::Error> {
+    config
+        .token
+        .clone()
+        .context("Missing bot token; set TATERBOARD_TOKEN or `token` in the config file")
+}
+
+fn app_id(config: &BotConfig) -> Result<u64, anyhow::Error> {
+    config
+        .app_id
+        .context("Missing app id; pass --app-id, set APP_ID, or set `app_id` in the config file")
+}
+
+fn open_storage(config: &BotConfig) -> Result<Arc<dyn Storage>, anyhow::Error> {
+    let kind = config.storage.unwrap_or(StorageKind::Json);
+    if kind == StorageKind::Memory {
+        log::warn!("Using memory storage, nothing will be saved once the bot stops");
+    }
+    kind.open(config.data_dir.clone())
+}
+
+/// Wait for a Ctrl-C, or a SIGTERM on unix
+async fn shutdown_signal() -> Result<(), anyhow::Error> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
 }
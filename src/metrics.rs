@@ -0,0 +1,429 @@
+//! Counters for what the bot's been up to, in Prometheus' text format.
+//!
+//! They're process-wide, so anything can bump them without having to be handed a registry.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::handler::GuildStats;
+
+/// Every counter the bot keeps
+pub static METRICS: Metrics = Metrics::new();
+
+/// Gateway events we handle
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    Ready,
+    GuildCreate,
+    ReactionAdd,
+    ReactionRemove,
+    Message,
+    MessageDelete,
+    Interaction,
+}
+
+impl Event {
+    const ALL: [Event; 7] = [
+        Event::Ready,
+        Event::GuildCreate,
+        Event::ReactionAdd,
+        Event::ReactionRemove,
+        Event::Message,
+        Event::MessageDelete,
+        Event::Interaction,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Event::Ready => "ready",
+            Event::GuildCreate => "guild_create",
+            Event::ReactionAdd => "reaction_add",
+            Event::ReactionRemove => "reaction_remove",
+            Event::Message => "message",
+            Event::MessageDelete => "message_delete",
+            Event::Interaction => "interaction",
+        }
+    }
+}
+
+/// Things that can happen to a pin
+#[derive(Debug, Clone, Copy)]
+pub enum PinAction {
+    Created,
+    Edited,
+    Deleted,
+    Moved,
+}
+
+impl PinAction {
+    const ALL: [PinAction; 4] = [
+        PinAction::Created,
+        PinAction::Edited,
+        PinAction::Deleted,
+        PinAction::Moved,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            PinAction::Created => "created",
+            PinAction::Edited => "edited",
+            PinAction::Deleted => "deleted",
+            PinAction::Moved => "moved",
+        }
+    }
+}
+
+/// Calls to Discord, one for each method of the `Discord` trait that makes one
+#[derive(Debug, Clone, Copy)]
+pub enum DiscordOp {
+    GetMessage,
+    SendPin,
+    EditPinHeader,
+    EditPin,
+    DeleteMessage,
+    CreateWebhook,
+    SendWebhookPin,
+    EditWebhookPin,
+    DeleteWebhookPin,
+    SendLog,
+}
+
+impl DiscordOp {
+    const ALL: [DiscordOp; 10] = [
+        DiscordOp::GetMessage,
+        DiscordOp::SendPin,
+        DiscordOp::EditPinHeader,
+        DiscordOp::EditPin,
+        DiscordOp::DeleteMessage,
+        DiscordOp::CreateWebhook,
+        DiscordOp::SendWebhookPin,
+        DiscordOp::EditWebhookPin,
+        DiscordOp::DeleteWebhookPin,
+        DiscordOp::SendLog,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            DiscordOp::GetMessage => "get_message",
+            DiscordOp::SendPin => "send_pin",
+            DiscordOp::EditPinHeader => "edit_pin_header",
+            DiscordOp::EditPin => "edit_pin",
+            DiscordOp::DeleteMessage => "delete_message",
+            DiscordOp::CreateWebhook => "create_webhook",
+            DiscordOp::SendWebhookPin => "send_webhook_pin",
+            DiscordOp::EditWebhookPin => "edit_webhook_pin",
+            DiscordOp::DeleteWebhookPin => "delete_webhook_pin",
+            DiscordOp::SendLog => "send_log",
+        }
+    }
+}
+
+pub struct Metrics {
+    events: [AtomicU64; Event::ALL.len()],
+    taters_added: AtomicU64,
+    taters_removed: AtomicU64,
+    tater_anomalies: AtomicU64,
+    pins: [AtomicU64; PinAction::ALL.len()],
+
+    discord_requests: [AtomicU64; DiscordOp::ALL.len()],
+    discord_errors: [AtomicU64; DiscordOp::ALL.len()],
+    discord_micros: [AtomicU64; DiscordOp::ALL.len()],
+
+    pluralkit_requests: AtomicU64,
+    pluralkit_errors: AtomicU64,
+    pluralkit_micros: AtomicU64,
+
+    lock_waits: AtomicU64,
+    lock_wait_micros: AtomicU64,
+
+    saves: AtomicU64,
+    last_save_micros: AtomicU64,
+    last_save_bytes: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            events: [const { AtomicU64::new(0) }; Event::ALL.len()],
+            taters_added: AtomicU64::new(0),
+            taters_removed: AtomicU64::new(0),
+            tater_anomalies: AtomicU64::new(0),
+            pins: [const { AtomicU64::new(0) }; PinAction::ALL.len()],
+            discord_requests: [const { AtomicU64::new(0) }; DiscordOp::ALL.len()],
+            discord_errors: [const { AtomicU64::new(0) }; DiscordOp::ALL.len()],
+            discord_micros: [const { AtomicU64::new(0) }; DiscordOp::ALL.len()],
+            pluralkit_requests: AtomicU64::new(0),
+            pluralkit_errors: AtomicU64::new(0),
+            pluralkit_micros: AtomicU64::new(0),
+            lock_waits: AtomicU64::new(0),
+            lock_wait_micros: AtomicU64::new(0),
+            saves: AtomicU64::new(0),
+            last_save_micros: AtomicU64::new(0),
+            last_save_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn event(&self, event: Event) {
+        self.events[event as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tater_added(&self) {
+        self.taters_added.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tater_removed(&self) {
+        self.taters_removed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tater_anomaly(&self) {
+        self.tater_anomalies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pin(&self, action: PinAction) {
+        self.pins[action as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A call to Discord finished, one way or another
+    pub fn discord_request(&self, op: DiscordOp, took: Duration, ok: bool) {
+        let idx = op as usize;
+        self.discord_requests[idx].fetch_add(1, Ordering::Relaxed);
+        self.discord_micros[idx].fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+        if !ok {
+            self.discord_errors[idx].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A call to PluralKit's API finished, one way or another
+    pub fn pluralkit_request(&self, took: Duration, ok: bool) {
+        self.pluralkit_requests.fetch_add(1, Ordering::Relaxed);
+        self.pluralkit_micros
+            .fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+        if !ok {
+            self.pluralkit_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Someone got a guild's lock after waiting this long
+    pub fn lock_wait(&self, waited: Duration) {
+        self.lock_waits.fetch_add(1, Ordering::Relaxed);
+        self.lock_wait_micros
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Everything was saved
+    pub fn saved(&self, took: Duration, bytes: u64) {
+        self.saves.fetch_add(1, Ordering::Relaxed);
+        self.last_save_micros
+            .store(took.as_micros() as u64, Ordering::Relaxed);
+        self.last_save_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Everything in Prometheus' text format, along with the given guilds' stats
+    pub fn render(&self, guilds: &[GuildStats]) -> String {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        let secs = |counter: &AtomicU64| {
+            (counter.load(Ordering::Relaxed) as f64 / 1_000_000.0).to_string()
+        };
+        let by_op = |counters: &[AtomicU64], value: &dyn Fn(&AtomicU64) -> String| {
+            DiscordOp::ALL
+                .iter()
+                .map(|op| {
+                    (
+                        format!("op=\"{}\"", op.name()),
+                        value(&counters[*op as usize]),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let by_guild = |value: &dyn Fn(&GuildStats) -> u64| {
+            guilds
+                .iter()
+                .map(|stats| {
+                    (
+                        format!("guild=\"{}\"", stats.guild),
+                        value(stats).to_string(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut out = String::new();
+        family(
+            &mut out,
+            "taterboard_events_total",
+            "counter",
+            "Gateway events handled",
+            Event::ALL
+                .iter()
+                .map(|event| {
+                    (
+                        format!("type=\"{}\"", event.name()),
+                        get(&self.events[*event as usize]),
+                    )
+                })
+                .collect(),
+        );
+        family(
+            &mut out,
+            "taterboard_taters_added_total",
+            "counter",
+            "Taters counted",
+            unlabelled(get(&self.taters_added)),
+        );
+        family(
+            &mut out,
+            "taterboard_taters_removed_total",
+            "counter",
+            "Taters taken back",
+            unlabelled(get(&self.taters_removed)),
+        );
+        family(
+            &mut out,
+            "taterboard_tater_anomalies_total",
+            "counter",
+            "Tater changes that didn't add up, like taking back one that was never counted",
+            unlabelled(get(&self.tater_anomalies)),
+        );
+        family(
+            &mut out,
+            "taterboard_pins_total",
+            "counter",
+            "Changes made to pins",
+            PinAction::ALL
+                .iter()
+                .map(|action| {
+                    (
+                        format!("action=\"{}\"", action.name()),
+                        get(&self.pins[*action as usize]),
+                    )
+                })
+                .collect(),
+        );
+        family(
+            &mut out,
+            "taterboard_discord_requests_total",
+            "counter",
+            "Calls to Discord",
+            by_op(&self.discord_requests, &get),
+        );
+        family(
+            &mut out,
+            "taterboard_discord_errors_total",
+            "counter",
+            "Calls to Discord that failed",
+            by_op(&self.discord_errors, &get),
+        );
+        family(
+            &mut out,
+            "taterboard_discord_request_seconds_total",
+            "counter",
+            "Time spent waiting on Discord",
+            by_op(&self.discord_micros, &secs),
+        );
+        family(
+            &mut out,
+            "taterboard_pluralkit_requests_total",
+            "counter",
+            "Calls to PluralKit, to find out who's behind proxied messages",
+            unlabelled(get(&self.pluralkit_requests)),
+        );
+        family(
+            &mut out,
+            "taterboard_pluralkit_errors_total",
+            "counter",
+            "Calls to PluralKit that failed",
+            unlabelled(get(&self.pluralkit_errors)),
+        );
+        family(
+            &mut out,
+            "taterboard_pluralkit_request_seconds_total",
+            "counter",
+            "Time spent waiting on PluralKit",
+            unlabelled(secs(&self.pluralkit_micros)),
+        );
+        family(
+            &mut out,
+            "taterboard_lock_waits_total",
+            "counter",
+            "Times a guild's lock was taken",
+            unlabelled(get(&self.lock_waits)),
+        );
+        family(
+            &mut out,
+            "taterboard_lock_wait_seconds_total",
+            "counter",
+            "Time spent waiting for guild locks",
+            unlabelled(secs(&self.lock_wait_micros)),
+        );
+        family(
+            &mut out,
+            "taterboard_saves_total",
+            "counter",
+            "Times everything was saved",
+            unlabelled(get(&self.saves)),
+        );
+        family(
+            &mut out,
+            "taterboard_last_save_seconds",
+            "gauge",
+            "How long the last save took",
+            unlabelled(secs(&self.last_save_micros)),
+        );
+        family(
+            &mut out,
+            "taterboard_last_save_bytes",
+            "gauge",
+            "How much the last save wrote",
+            unlabelled(get(&self.last_save_bytes)),
+        );
+        family(
+            &mut out,
+            "taterboard_guilds",
+            "gauge",
+            "Guilds we have data for",
+            unlabelled(guilds.len().to_string()),
+        );
+        family(
+            &mut out,
+            "taterboard_guild_taters_given",
+            "gauge",
+            "Taters given in each guild",
+            by_guild(&|stats| stats.taters_given),
+        );
+        family(
+            &mut out,
+            "taterboard_guild_tatered_messages",
+            "gauge",
+            "Messages with taters on them in each guild",
+            by_guild(&|stats| stats.tatered_messages),
+        );
+        family(
+            &mut out,
+            "taterboard_guild_max_taters",
+            "gauge",
+            "Most taters on one message in each guild",
+            by_guild(&|stats| stats.max_taters),
+        );
+        out
+    }
+}
+
+/// Write out one metric, with each of its samples as labels and a value
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: Vec<(String, String)>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+fn unlabelled(value: String) -> Vec<(String, String)> {
+    vec![(String::new(), value)]
+}
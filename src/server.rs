@@ -0,0 +1,134 @@
+//! A tiny HTTP server so the bot can be scraped for metrics and checked on.
+//!
+//! It only ever needs to answer a handful of GETs from local tooling,
+//! so it reads the request line and ignores everything else.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    handler::{HandlerWrapper, Health},
+    metrics::METRICS,
+};
+
+/// Longest we'll spend on one request before hanging up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const METRICS_TYPE: &str = "text/plain; version=0.0.4";
+const JSON_TYPE: &str = "application/json";
+const TEXT_TYPE: &str = "text/plain";
+
+/// Answer requests on `addr` forever
+pub async fn serve(addr: SocketAddr, wrapper: HandlerWrapper) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("listening on {}", addr))?;
+    log::info!(
+        "Serving metrics on http://{0}/metrics and health checks on http://{0}/healthz and /readyz",
+        addr
+    );
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let wrapper = wrapper.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &wrapper)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(oh_no)) => log::debug!("Answering {}: {:?}", peer, oh_no),
+                Err(_) => log::debug!("Answering {}: timed out", peer),
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, wrapper: &HandlerWrapper) -> Result<(), anyhow::Error> {
+    // Read up to the end of the headers, or as much as fits
+    let mut buf = [0; 4096];
+    let mut len = 0;
+    while len < buf.len() {
+        let read = stream.read(&mut buf[len..]).await?;
+        if read == 0 {
+            break;
+        }
+        len += read;
+        if buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = request.split_whitespace();
+    let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let stats = wrapper.guild_stats().await;
+            ("200 OK", METRICS_TYPE, METRICS.render(&stats))
+        }
+        (Some("GET"), Some("/healthz")) => {
+            let health = wrapper.health().await;
+            (
+                ok_or_unavailable(health.healthy()),
+                JSON_TYPE,
+                health_json(&health),
+            )
+        }
+        (Some("GET"), Some("/readyz")) => {
+            let health = wrapper.health().await;
+            (
+                ok_or_unavailable(health.ready()),
+                JSON_TYPE,
+                health_json(&health),
+            )
+        }
+        (Some("GET"), _) => ("404 Not Found", TEXT_TYPE, "Not found\n".to_owned()),
+        _ => (
+            "405 Method Not Allowed",
+            TEXT_TYPE,
+            "Only GET is supported\n".to_owned(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn ok_or_unavailable(ok: bool) -> &'static str {
+    if ok {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    }
+}
+
+fn health_json(health: &Health) -> String {
+    let last_save = health
+        .last_save
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs());
+    let json = serde_json::json!({
+        "healthy": health.healthy(),
+        "ready": health.ready(),
+        "connected": health.connected,
+        "accepting_events": health.accepting_events,
+        "locks_ok": health.locks_ok,
+        "last_save": last_save,
+        "last_save_failed": health.last_save_error.is_some(),
+        "last_save_error": health.last_save_error,
+    });
+    format!("{}\n", json)
+}
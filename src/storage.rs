@@ -0,0 +1,136 @@
+//! Where saved data lives between runs.
+//!
+//! Storage only deals in named blobs of json, like `<guild_id>_taters.json`.
+//! What goes in them is up to the handler.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Somewhere to keep saved data
+pub trait Storage: Send + Sync {
+    /// Names of everything that's been saved
+    fn list(&self) -> Result<Vec<String>, anyhow::Error>;
+
+    /// Read something back, or None if it was never saved
+    fn read(&self, name: &str) -> Result<Option<String>, anyhow::Error>;
+
+    /// Save something, replacing whatever was there
+    fn write(&self, name: &str, contents: &str) -> Result<(), anyhow::Error>;
+
+    /// Get rid of something. It's fine if it was never saved.
+    fn remove(&self, name: &str) -> Result<(), anyhow::Error>;
+}
+
+/// Which kind of storage to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    /// `.json` files in the data directory
+    Json,
+    /// Nothing is kept once the bot stops. Handy for trying things out.
+    Memory,
+}
+
+impl StorageKind {
+    /// Open this kind of storage. `data_dir` is only needed for `Json`.
+    pub fn open(self, data_dir: Option<PathBuf>) -> Result<Arc<dyn Storage>, anyhow::Error> {
+        Ok(match self {
+            StorageKind::Json => {
+                let data_dir = data_dir.context(
+                    "No data directory; pass --data-dir or set `data_dir` in the config file",
+                )?;
+                Arc::new(JsonDirStorage::new(data_dir)?)
+            }
+            StorageKind::Memory => Arc::new(MemoryStorage::default()),
+        })
+    }
+}
+
+/// Keeps each blob as a file in one directory
+pub struct JsonDirStorage {
+    dir: PathBuf,
+}
+
+impl JsonDirStorage {
+    /// Use the given directory, making it if it doesn't exist yet
+    pub fn new(dir: PathBuf) -> Result<Self, anyhow::Error> {
+        fs::create_dir_all(&dir).with_context(|| format!("making data directory {:?}", dir))?;
+        log::debug!("Saving data to {:?}", dir);
+        Ok(Self { dir })
+    }
+}
+
+impl Storage for JsonDirStorage {
+    fn list(&self) -> Result<Vec<String>, anyhow::Error> {
+        let mut names = Vec::new();
+        for entry in self
+            .dir
+            .read_dir()
+            .with_context(|| format!("reading data directory {:?}", self.dir))?
+        {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn read(&self, name: &str) -> Result<Option<String>, anyhow::Error> {
+        match fs::read_to_string(self.dir.join(name)) {
+            Ok(it) => Ok(Some(it)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {}", name)),
+        }
+    }
+
+    fn write(&self, name: &str, contents: &str) -> Result<(), anyhow::Error> {
+        fs::write(self.dir.join(name), contents).with_context(|| format!("writing {}", name))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), anyhow::Error> {
+        match fs::remove_file(self.dir.join(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("removing {}", name))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps everything in memory, so it's gone when the bot stops
+#[derive(Default)]
+pub struct MemoryStorage {
+    blobs: Mutex<HashMap<String, String>>,
+}
+
+impl Storage for MemoryStorage {
+    fn list(&self) -> Result<Vec<String>, anyhow::Error> {
+        Ok(self.blobs.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn read(&self, name: &str) -> Result<Option<String>, anyhow::Error> {
+        Ok(self.blobs.lock().unwrap().get(name).cloned())
+    }
+
+    fn write(&self, name: &str, contents: &str) -> Result<(), anyhow::Error> {
+        self.blobs
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), contents.to_owned());
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<(), anyhow::Error> {
+        self.blobs.lock().unwrap().remove(name);
+        Ok(())
+    }
+}